@@ -1,5 +1,7 @@
 extern crate embed_resource;
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 fn main() {
     let target = env::var("TARGET").unwrap();
@@ -7,4 +9,45 @@ fn main() {
         // on windows we will set our game icon as icon for the executable
         embed_resource::compile("build/windows/icon.rc");
     }
+    println!("cargo:rerun-if-changed=build/windows");
+
+    // peers compare this hash before starting a session, so that different builds never get matched
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rustc-env=GAME_HASH={}", hash_sources(Path::new("src")));
+}
+
+/// FNV-1a hash over all source files.
+/// We don't use the std hasher because its output is not guaranteed to be stable across Rust versions.
+fn hash_sources(dir: &Path) -> u64 {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files);
+    files.sort();
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+    for file in files {
+        let contents = fs::read(&file).unwrap();
+        let path = file.to_string_lossy().replace('\\', "/");
+        // ignore line endings, as git may check out the sources with different ones depending on the platform
+        let bytes = version
+            .bytes()
+            .chain(path.bytes())
+            .chain(contents.into_iter().filter(|byte| *byte != b'\r'));
+        for byte in bytes {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
 }
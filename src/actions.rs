@@ -1,5 +1,10 @@
-use crate::networking::protocol::{InputFlags, InputProtocol, LocalHandles};
-use bevy::{log, prelude::*};
+use crate::config::MAX_PREDICTION_WINDOW;
+use crate::kill_cam::KillCam;
+use crate::networking::error::NetworkingError;
+use crate::networking::protocol::{dequantise_aim, InputFlags, InputProtocol, LocalHandles};
+use crate::pause::Pause;
+use crate::player::{MainCamera, Player};
+use bevy::{log, prelude::*, utils::HashSet};
 use ggrs::{InputStatus, PlayerHandle};
 pub struct ActionsPlugin;
 
@@ -14,29 +19,59 @@ impl Plugin for ActionsPlugin {
 #[derive(Debug, Component, Reflect, Default, Clone)]
 pub struct Actions {
     pub player_movement: Option<Vec2>,
+    /// Normalized direction the player is aiming in, if they are aiming at all
+    pub aim: Option<Vec2>,
+    pub fire: bool,
+    pub dash: bool,
 }
 
 pub fn set_movement_actions(
     mut actions: ResMut<Vec<Actions>>,
     inputs: Res<Vec<(InputProtocol, InputStatus)>>,
+    frame_count: Res<FrameCount>,
+    mut reported: Local<HashSet<(u32, PlayerHandle)>>,
+    mut errors: EventWriter<NetworkingError>,
 ) {
+    let frame = frame_count.frame;
+    // frames are resimulated on rollbacks, but never further back than the prediction window
+    reported.retain(|(reported_frame, _)| {
+        *reported_frame <= frame && *reported_frame + MAX_PREDICTION_WINDOW as u32 >= frame
+    });
     *actions = inputs
         .iter()
-        .map(|(protocol, status)| parse_protocol_to_actions(protocol, *status))
+        .enumerate()
+        .map(|(handle, (protocol, status))| {
+            parse_protocol_to_actions(protocol, *status).unwrap_or_else(|reason| {
+                // Every peer receives the same input, so ignoring it keeps everyone in sync until the session ends
+                if reported.insert((frame, handle)) {
+                    errors.send(NetworkingError::ProtocolViolation { handle, reason });
+                }
+                Actions::default()
+            })
+        })
         .collect();
 }
 
-fn parse_protocol_to_actions(protocol: &InputProtocol, status: InputStatus) -> Actions {
+fn parse_protocol_to_actions(
+    protocol: &InputProtocol,
+    status: InputStatus,
+) -> Result<Actions, String> {
     let mut action = Actions::default();
     if status == InputStatus::Disconnected {
         log::warn!("Player disconnected");
-        return action;
+        return Ok(action);
     }
 
-    let input = InputFlags::try_from(*protocol).unwrap();
+    let input = InputFlags::try_from(*protocol)?;
     if input.is_empty() {
-        return action;
+        return Ok(action);
+    }
+
+    if input.contains(InputFlags::AIM) {
+        action.aim = Some(dequantise_aim(protocol.aim));
     }
+    action.fire = input.contains(InputFlags::FIRE);
+    action.dash = input.contains(InputFlags::DASH);
 
     let mut player_movement = Vec2::ZERO;
     if input.contains(InputFlags::LEFT) {
@@ -53,12 +88,12 @@ fn parse_protocol_to_actions(protocol: &InputProtocol, status: InputStatus) -> A
     }
 
     if player_movement == Vec2::ZERO {
-        return action;
+        return Ok(action);
     }
 
     player_movement = player_movement.normalize();
     action.player_movement = Some(player_movement);
-    action
+    Ok(action)
 }
 
 enum GameControl {
@@ -67,6 +102,7 @@ enum GameControl {
     Left,
     Right,
     Fire,
+    Dash,
 }
 
 macro_rules! generate_bindings {
@@ -108,15 +144,30 @@ generate_bindings! {
     GameControl::Down => [KeyCode::S, KeyCode::Down,],
     GameControl::Left => [KeyCode::A, KeyCode::Left,],
     GameControl::Right => [KeyCode::D, KeyCode::Right,],
-    GameControl::Fire => [KeyCode::Space, KeyCode::Return]
+    GameControl::Fire => [KeyCode::Space, KeyCode::Return,],
+    GameControl::Dash => [KeyCode::LShift, KeyCode::RShift]
 }
 
+/// Sticks moved less than this are treated as not aiming
+const AIM_DEADZONE: f32 = 0.3;
+
+#[allow(clippy::too_many_arguments)]
 pub fn create_input_protocol(
-    _handle: In<PlayerHandle>,
+    handle: In<PlayerHandle>,
     keyboard_input: Res<Input<KeyCode>>,
-    _local_handles: Res<LocalHandles>,
+    local_handles: Res<LocalHandles>,
+    windows: Res<Windows>,
+    gamepads: Res<Gamepads>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    camera_query: Query<(&GlobalTransform, &OrthographicProjection), With<MainCamera>>,
+    player_query: Query<(&Transform, &Player)>,
+    pause: Res<Pause>,
+    kill_cam: Option<Res<KillCam>>,
 ) -> InputProtocol {
     let mut input = InputFlags::empty();
+    if pause.menu_open {
+        return input.into();
+    }
 
     if GameControl::Up.pressed(&keyboard_input) {
         input |= InputFlags::UP;
@@ -133,6 +184,130 @@ pub fn create_input_protocol(
     if GameControl::Fire.pressed(&keyboard_input) {
         input |= InputFlags::FIRE;
     }
+    if GameControl::Dash.pressed(&keyboard_input) {
+        input |= InputFlags::DASH;
+    }
+
+    let player_position = player_query
+        .iter()
+        .find(|(_, player)| player.handle() == handle.0)
+        .map(|(transform, _)| transform.translation.truncate());
+    let aim = player_position.and_then(|player_position| {
+        local_gamepad(handle.0, &local_handles, &gamepads)
+            .and_then(|gamepad| aim_from_gamepad(gamepad, &gamepad_axes))
+            .or_else(|| {
+                // the kill-cam moves the camera, but the player aims at the match they saw before it
+                let camera = match &kill_cam {
+                    Some(kill_cam) => Some(kill_cam.match_camera()),
+                    None => camera_query
+                        .get_single()
+                        .ok()
+                        .map(|(transform, projection)| {
+                            (Transform::from(*transform), projection.scale)
+                        }),
+                };
+                camera
+                    .and_then(|camera| aim_from_cursor(&windows, camera))
+                    .map(|cursor| cursor - player_position)
+            })
+    });
 
-    input.into()
+    match aim {
+        Some(aim) if aim != Vec2::ZERO => InputProtocol::with_aim(input, aim),
+        _ => input.into(),
+    }
+}
+
+/// The n-th local player uses the n-th connected gamepad, so two players on one machine don't share a stick
+fn local_gamepad(
+    handle: PlayerHandle,
+    local_handles: &LocalHandles,
+    gamepads: &Gamepads,
+) -> Option<Gamepad> {
+    let index = local_handles
+        .handles
+        .iter()
+        .position(|local_handle| *local_handle == handle)?;
+    let mut gamepads: Vec<_> = gamepads.iter().copied().collect();
+    gamepads.sort_by_key(|gamepad| gamepad.0);
+    gamepads.get(index).copied()
+}
+
+fn aim_from_gamepad(gamepad: Gamepad, gamepad_axes: &Axis<GamepadAxis>) -> Option<Vec2> {
+    let x = gamepad_axes.get(GamepadAxis(gamepad, GamepadAxisType::RightStickX))?;
+    let y = gamepad_axes.get(GamepadAxis(gamepad, GamepadAxisType::RightStickY))?;
+    let stick = Vec2::new(x, y);
+    (stick.length() > AIM_DEADZONE).then_some(stick)
+}
+
+/// Returns the position of the cursor in world space, seen through a camera with the given transform and scale
+fn aim_from_cursor(windows: &Windows, (camera_transform, scale): (Transform, f32)) -> Option<Vec2> {
+    let window = windows.get_primary()?;
+    let cursor = window.cursor_position()?;
+    let window_size = Vec2::new(window.width(), window.height());
+    let offset = (cursor - window_size / 2.) * scale;
+    Some(camera_transform.translation.truncate() + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(protocol: InputProtocol) -> Actions {
+        parse_protocol_to_actions(&protocol, InputStatus::Confirmed).unwrap()
+    }
+
+    #[test]
+    fn opposite_directions_cancel_out() {
+        let actions = parse((InputFlags::LEFT | InputFlags::RIGHT).into());
+        assert_eq!(actions.player_movement, None);
+    }
+
+    #[test]
+    fn diagonal_movement_is_normalized() {
+        let actions = parse((InputFlags::UP | InputFlags::RIGHT).into());
+        let movement = actions.player_movement.unwrap();
+        assert!((movement.length() - 1.).abs() < 1e-6);
+        assert!(movement.x > 0. && movement.y > 0.);
+    }
+
+    #[test]
+    fn aim_is_only_read_with_aim_flag() {
+        let mut protocol = InputProtocol::new(InputFlags::FIRE);
+        protocol.aim = 64;
+        let actions = parse(protocol);
+        assert!(actions.fire);
+        assert_eq!(actions.aim, None);
+
+        let actions = parse(InputProtocol::with_aim(InputFlags::FIRE, Vec2::Y));
+        assert!(actions.aim.unwrap().distance(Vec2::Y) < 1e-6);
+    }
+
+    #[test]
+    fn dash_is_read_without_movement() {
+        let actions = parse(InputFlags::DASH.into());
+        assert!(actions.dash);
+        assert_eq!(actions.player_movement, None);
+    }
+
+    #[test]
+    fn disconnected_players_do_nothing() {
+        let protocol = InputProtocol::with_aim(
+            InputFlags::UP | InputFlags::FIRE | InputFlags::DASH,
+            Vec2::X,
+        );
+        let actions = parse_protocol_to_actions(&protocol, InputStatus::Disconnected).unwrap();
+        assert_eq!(actions.player_movement, None);
+        assert!(!actions.fire);
+        assert!(!actions.dash);
+    }
+
+    #[test]
+    fn invalid_bits_are_rejected() {
+        let protocol = InputProtocol {
+            input: 1 << 7,
+            ..InputProtocol::new(InputFlags::empty())
+        };
+        assert!(parse_protocol_to_actions(&protocol, InputStatus::Confirmed).is_err());
+    }
 }
@@ -0,0 +1,155 @@
+use crate::actions::Actions;
+use crate::events::{GameplayEventKind, GameplayEvents};
+use crate::player::Player;
+use crate::GameState;
+use bevy::prelude::*;
+
+pub struct AnimationPlugin;
+
+/// Number of frames per row of the player sprite sheet, see [`crate::loading::TextureAssets`]
+const SHEET_COLUMNS: usize = 4;
+
+/// This plugin shows the current frame of every player's [`AnimationState`].
+/// The state itself is advanced in the rollback schedule, so animations stay consistent after rollbacks.
+impl Plugin for AnimationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(GameState::Playing).with_system(show_animation_frames),
+        );
+    }
+}
+
+#[derive(Reflect, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect_value(PartialEq, Hash)]
+pub enum Animation {
+    #[default]
+    Idle,
+    Run,
+    Shoot,
+    Hit,
+    Die,
+}
+
+impl Animation {
+    /// Row of the player sprite sheet
+    fn row(self) -> usize {
+        match self {
+            Animation::Idle => 0,
+            Animation::Run => 1,
+            Animation::Shoot => 2,
+            Animation::Hit => 3,
+            Animation::Die => 4,
+        }
+    }
+
+    /// Rollback frames each sprite sheet frame is shown for
+    fn ticks_per_frame(self) -> u32 {
+        match self {
+            Animation::Idle => 10,
+            Animation::Run => 6,
+            Animation::Shoot | Animation::Hit => 3,
+            Animation::Die => 6,
+        }
+    }
+
+    /// One-shot animations play once and can't be interrupted by lower priority animations
+    fn looping(self) -> bool {
+        matches!(self, Animation::Idle | Animation::Run)
+    }
+
+    fn priority(self) -> u8 {
+        match self {
+            Animation::Idle | Animation::Run => 0,
+            Animation::Shoot => 1,
+            Animation::Hit => 2,
+            Animation::Die => 3,
+        }
+    }
+
+    fn duration(self) -> u32 {
+        self.ticks_per_frame() * SHEET_COLUMNS as u32
+    }
+}
+
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect(Component)]
+pub struct AnimationState {
+    pub animation: Animation,
+    /// Rollback frames since the animation started
+    pub ticks: u32,
+}
+
+impl AnimationState {
+    fn play(&mut self, animation: Animation) {
+        if self.animation != animation {
+            *self = AnimationState {
+                animation,
+                ticks: 0,
+            };
+        }
+    }
+
+    fn is_playing_one_shot(&self) -> bool {
+        !self.animation.looping() && self.ticks < self.animation.duration()
+    }
+
+    /// Index into the player sprite sheet
+    pub fn sheet_index(&self) -> usize {
+        let frame = (self.ticks / self.animation.ticks_per_frame()) as usize;
+        let frame = if self.animation.looping() {
+            frame % SHEET_COLUMNS
+        } else {
+            frame.min(SHEET_COLUMNS - 1)
+        };
+        self.animation.row() * SHEET_COLUMNS + frame
+    }
+}
+
+/// Rollback system, runs after everything else happened in a frame
+pub fn update_animations(
+    actions: Res<Vec<Actions>>,
+    events: Res<GameplayEvents>,
+    mut player_query: Query<(&Player, &mut AnimationState)>,
+) {
+    for (player, mut state) in player_query.iter_mut() {
+        state.ticks += 1;
+
+        let triggered = events
+            .iter()
+            .filter(|(event, _)| event.frame == events.frame() && event.player == player.handle())
+            .map(|(event, _)| match event.kind {
+                GameplayEventKind::Fire => Animation::Shoot,
+                GameplayEventKind::Hit => Animation::Hit,
+                GameplayEventKind::Death => Animation::Die,
+            })
+            .max_by_key(|animation| animation.priority());
+        if let Some(animation) = triggered {
+            if !state.is_playing_one_shot() || animation.priority() >= state.animation.priority() {
+                // restart the animation, e.g. when firing again before the shoot animation finished
+                *state = AnimationState {
+                    animation,
+                    ticks: 0,
+                };
+            }
+            continue;
+        }
+        if state.is_playing_one_shot() {
+            continue;
+        }
+
+        if actions[player.handle()].player_movement.is_some() {
+            state.play(Animation::Run);
+        } else {
+            state.play(Animation::Idle);
+        }
+    }
+}
+
+fn show_animation_frames(mut player_query: Query<(&AnimationState, &mut TextureAtlasSprite)>) {
+    for (state, mut sprite) in player_query.iter_mut() {
+        let index = state.sheet_index();
+        if sprite.index != index {
+            sprite.index = index;
+        }
+    }
+}
@@ -1,35 +1,199 @@
 use crate::actions::Actions;
+use crate::config::NUM_PLAYERS;
+use crate::events::{GameplayEvent, GameplayEventKind, GameplayEvents, PresentedEvents};
 use crate::loading::AudioAssets;
+use crate::player::{MainCamera, Player};
+use crate::settings::Settings;
 use crate::GameState;
 use bevy::prelude::*;
-use bevy_kira_audio::{Audio, AudioPlugin};
+use bevy_kira_audio::{Audio, AudioChannel, AudioPlugin};
 
 pub struct InternalAudioPlugin;
 
+/// Half the width of the area in which sounds are panned from fully left to fully right
+const PANNING_DISTANCE: f32 = 8.;
+/// Number of one-shot effects of a single player that can play at the same time
+const EFFECT_SLOTS: usize = 4;
+
 // This plugin is responsible to control the game audio
+// Music and effects use separate channels, and each player has their own movement and effects channels,
+// so they can be panned individually.
 impl Plugin for InternalAudioPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(AudioPlugin)
+            .init_resource::<AudioChannels>()
+            .init_resource::<EffectSlots>()
+            .init_resource::<PresentedEvents<usize>>()
+            .add_system(apply_volume)
+            .add_system_set(SystemSet::on_exit(GameState::Loading).with_system(start_music))
             .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(start_audio))
             .add_system_set(
-                SystemSet::on_update(GameState::Playing).with_system(control_flying_sound),
-            );
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(control_flying_sound)
+                    .with_system(pan_player_channels)
+                    .with_system(play_gameplay_sounds),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(stop_audio));
+    }
+}
+
+struct AudioChannels {
+    music: AudioChannel,
+    /// Looped movement sound of each player
+    movement: Vec<AudioChannel>,
+    /// One-shot effects of each player, each playing in its own slot.
+    /// Stopping a slot is how we cancel the effect of an event that got rolled back.
+    effects: Vec<Vec<AudioChannel>>,
+}
+
+impl Default for AudioChannels {
+    fn default() -> Self {
+        Self {
+            music: AudioChannel::new("music".to_owned()),
+            movement: (0..NUM_PLAYERS)
+                .map(|handle| AudioChannel::new(format!("movement_{}", handle)))
+                .collect(),
+            effects: (0..NUM_PLAYERS)
+                .map(|handle| {
+                    (0..EFFECT_SLOTS)
+                        .map(|slot| AudioChannel::new(format!("effects_{}_{}", handle, slot)))
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+impl AudioChannels {
+    fn player_channels(&self) -> impl Iterator<Item = &AudioChannel> {
+        self.movement.iter().chain(self.effects.iter().flatten())
+    }
+}
+
+/// Which event each effect slot played last, so a slot is only stopped for the event it belongs to
+#[derive(Default)]
+struct EffectSlots {
+    playing: [[Option<GameplayEvent>; EFFECT_SLOTS]; NUM_PLAYERS],
+    next: [usize; NUM_PLAYERS],
+}
+
+impl EffectSlots {
+    /// Reuses the slot of the oldest effect of the player
+    fn claim(&mut self, event: GameplayEvent) -> usize {
+        let slot = self.next[event.player];
+        self.next[event.player] = (slot + 1) % EFFECT_SLOTS;
+        self.playing[event.player][slot] = Some(event);
+        slot
+    }
+
+    /// Returns whether the slot was still playing the effect of the event
+    fn release(&mut self, event: GameplayEvent, slot: usize) -> bool {
+        let playing = &mut self.playing[event.player][slot];
+        if *playing == Some(event) {
+            *playing = None;
+            true
+        } else {
+            false
+        }
     }
 }
 
-fn start_audio(audio_assets: Res<AudioAssets>, audio: Res<Audio>) {
-    audio.set_volume(0.3);
-    audio.play_looped(audio_assets.flying.clone());
-    audio.pause();
+fn apply_volume(settings: Res<Settings>, audio: Res<Audio>, channels: Res<AudioChannels>) {
+    if !settings.is_changed() {
+        return;
+    }
+    audio.set_volume_in_channel(
+        settings.master_volume * settings.music_volume,
+        &channels.music,
+    );
+    for channel in channels.player_channels() {
+        audio.set_volume_in_channel(settings.master_volume * settings.sfx_volume, channel);
+    }
+}
+
+fn start_music(audio_assets: Res<AudioAssets>, audio: Res<Audio>, channels: Res<AudioChannels>) {
+    audio.play_looped_in_channel(audio_assets.music.clone(), &channels.music);
+}
+
+fn start_audio(
+    audio_assets: Res<AudioAssets>,
+    audio: Res<Audio>,
+    channels: Res<AudioChannels>,
+    mut effect_slots: ResMut<EffectSlots>,
+    mut presented_events: ResMut<PresentedEvents<usize>>,
+) {
+    *effect_slots = default();
+    presented_events.clear();
+    for channel in &channels.movement {
+        audio.play_looped_in_channel(audio_assets.flying.clone(), channel);
+        audio.pause_channel(channel);
+    }
+}
+
+fn stop_audio(audio: Res<Audio>, channels: Res<AudioChannels>) {
+    for channel in channels.player_channels() {
+        audio.stop_channel(channel);
+    }
+}
+
+fn control_flying_sound(
+    actions: Res<Vec<Actions>>,
+    audio: Res<Audio>,
+    channels: Res<AudioChannels>,
+) {
+    for (actions, channel) in actions.iter().zip(channels.movement.iter()) {
+        if actions.player_movement.is_some() {
+            audio.resume_channel(channel);
+        } else {
+            audio.pause_channel(channel);
+        }
+    }
+}
+
+/// Pans each player's sounds to where they are on the screen
+fn pan_player_channels(
+    audio: Res<Audio>,
+    channels: Res<AudioChannels>,
+    camera_query: Query<&GlobalTransform, With<MainCamera>>,
+    player_query: Query<(&GlobalTransform, &Player), Changed<GlobalTransform>>,
+) {
+    let camera = match camera_query.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    for (transform, player) in player_query.iter() {
+        let offset = transform.translation.x - camera.translation.x;
+        let panning = (0.5 + offset / PANNING_DISTANCE / 2.).clamp(0., 1.);
+        audio.set_panning_in_channel(panning, &channels.movement[player.handle()]);
+        for channel in &channels.effects[player.handle()] {
+            audio.set_panning_in_channel(panning, channel);
+        }
+    }
 }
 
-fn control_flying_sound(actions: Res<Vec<Actions>>, audio: Res<Audio>) {
-    if actions
-        .iter()
-        .any(|actions| actions.player_movement.is_some())
-    {
-        audio.resume();
-    } else {
-        audio.pause()
+/// Plays a sound for every new gameplay event.
+/// Sounds of events that turned out to be mispredicted are stopped, and resimulated events don't play twice.
+fn play_gameplay_sounds(
+    audio: Res<Audio>,
+    audio_assets: Res<AudioAssets>,
+    channels: Res<AudioChannels>,
+    events: Res<GameplayEvents>,
+    mut effect_slots: ResMut<EffectSlots>,
+    mut presented_events: ResMut<PresentedEvents<usize>>,
+) {
+    let invalidated = presented_events.update(&events, |event, _| {
+        let sound = match event.kind {
+            GameplayEventKind::Fire => &audio_assets.fire,
+            GameplayEventKind::Hit => &audio_assets.hit,
+            GameplayEventKind::Death => &audio_assets.death,
+        };
+        let slot = effect_slots.claim(*event);
+        audio.play_in_channel(sound.clone(), &channels.effects[event.player][slot]);
+        slot
+    });
+    for (event, slot) in invalidated {
+        if effect_slots.release(event, slot) {
+            audio.stop_channel(&channels.effects[event.player][slot]);
+        }
     }
 }
@@ -0,0 +1,215 @@
+//! Measures the cost of rollbacks: `cargo run --release -- bench`.
+//! Runs a scripted match headless, once as a sync test rolling back as far as the prediction window allows
+//! and once as a P2P match over a simulated connection, and reports the time per simulated frame,
+//! how long saving and restoring each rollback type takes and whether a worst-case rollback fits into a frame.
+
+use crate::config::{FPS, MAX_PREDICTION_WINDOW, NUM_PLAYERS};
+use crate::networking::budget::{frame_budget, RollbackTimings};
+use crate::networking::headless::{p2p_apps, run_until_frame, sync_test_app, InputScript};
+use crate::networking::protocol::{InputFlags, InputProtocol};
+use crate::networking::simulator::NetworkConditions;
+use crate::networking::RegisterRollbackTypes;
+use crate::player::spawn_position;
+use bevy::prelude::*;
+use bevy::reflect::TypeRegistry;
+use bevy_ggrs::Rollback;
+use clap::Parser;
+use std::time::{Duration, Instant};
+
+/// Name of the subcommand, has to be the first argument
+const SUBCOMMAND: &str = "bench";
+
+/// How often saving and restoring every rollback type is repeated to measure it
+const SNAPSHOT_ITERATIONS: u32 = 1000;
+
+/// Frames each player keeps running in the same direction
+const SCRIPT_SEGMENT: usize = FPS / 2;
+
+#[derive(Parser, Debug)]
+#[clap(about = "Measures how long rollbacks take and compares it to the frame budget")]
+struct BenchArgs {
+    /// Number of frames simulated in each scenario
+    #[clap(short, long, default_value_t = 10 * FPS as u32)]
+    frames: u32,
+    /// Simulated latency of the P2P scenario in milliseconds
+    #[clap(long, default_value_t = 100)]
+    latency: u64,
+    /// Maximum simulated random delay on top of the latency in milliseconds
+    #[clap(long, default_value_t = 30)]
+    jitter: u64,
+}
+
+/// Runs the benchmark if the game was started with the `bench` subcommand.
+/// Returns `false` if the game should start normally instead.
+pub fn run_if_requested() -> bool {
+    let mut args = std::env::args();
+    let executable = args.next();
+    if args.next().as_deref() != Some(SUBCOMMAND) {
+        return false;
+    }
+    let bench_args = BenchArgs::parse_from(executable.into_iter().chain(args));
+    if !bench(bench_args) {
+        std::process::exit(1);
+    }
+    true
+}
+
+/// Returns whether the rollbacks stayed within the frame budget
+fn bench(args: BenchArgs) -> bool {
+    let timeout =
+        Duration::from_secs_f64(2. * args.frames as f64 / FPS as f64) + Duration::from_secs(10);
+    let script = benchmark_script(args.frames);
+    let mut within_budget = true;
+
+    let check_distance = MAX_PREDICTION_WINDOW - 1;
+    println!(
+        "Sync test, rolling back {} frames every frame",
+        check_distance
+    );
+    let mut apps = [sync_test_app(script.clone(), check_distance)];
+    if !run_until_frame(&mut apps, args.frames, timeout) {
+        println!("Did not reach frame {} in time", args.frames);
+        return false;
+    }
+    within_budget &= report_timings(&apps[0]);
+    report_rollback_types(&mut apps[0].world);
+
+    let conditions = NetworkConditions {
+        latency: Duration::from_millis(args.latency),
+        jitter: Duration::from_millis(args.jitter),
+        ..default()
+    };
+    println!();
+    println!(
+        "P2P over a loopback with {:?} latency and {:?} jitter",
+        conditions.latency, conditions.jitter
+    );
+    let mut apps = p2p_apps(script, conditions);
+    if !run_until_frame(&mut apps, args.frames, timeout) {
+        println!("Did not reach frame {} in time", args.frames);
+        return false;
+    }
+    for (handle, app) in apps.iter().enumerate() {
+        println!("Peer {}:", handle);
+        within_budget &= report_timings(app);
+    }
+    within_budget
+}
+
+/// Both players run around and keep firing at each other, so there are always bullets to roll back
+fn benchmark_script(frames: u32) -> InputScript {
+    let directions = [
+        InputFlags::UP,
+        InputFlags::RIGHT,
+        InputFlags::DOWN,
+        InputFlags::LEFT,
+    ];
+    let mut script = InputScript::default();
+    for segment in 0..frames as usize / SCRIPT_SEGMENT + 1 {
+        for handle in 0..NUM_PLAYERS {
+            let direction = directions[(segment + handle) % directions.len()];
+            let aim = spawn_position((handle + 1) % NUM_PLAYERS) - spawn_position(handle);
+            let input = InputProtocol::with_aim(direction | InputFlags::FIRE, aim);
+            script = script.with(handle, SCRIPT_SEGMENT, input);
+        }
+    }
+    script
+}
+
+/// Prints the timings of the rollback schedule and returns whether a worst-case rollback fits into a frame
+fn report_timings(app: &App) -> bool {
+    let timings = app.world.resource::<RollbackTimings>();
+    let totals = timings.totals();
+    let average = totals.time / totals.frames.max(1) as u32;
+    let worst_case = timings.worst_case();
+    let within_budget = worst_case <= frame_budget();
+    println!(
+        "  {} frames simulated, {} of them resimulated",
+        totals.frames, totals.resimulated_frames
+    );
+    println!(
+        "  Frame time: {:.2?} on average, {:.2?} at most",
+        average, totals.slowest_frame
+    );
+    println!(
+        "  Snapshots between frames: {:.2?} on average, {:.2?} at most",
+        totals.snapshot_time / totals.frames.max(1) as u32,
+        totals.slowest_snapshot
+    );
+    println!(
+        "  Worst-case rollback of {} frames: {:.2?} of {:.2?} per frame ({})",
+        MAX_PREDICTION_WINDOW,
+        worst_case,
+        frame_budget(),
+        if within_budget { "ok" } else { "exceeded" }
+    );
+    within_budget
+}
+
+/// Prints how long saving and restoring each rollback type takes for the current world,
+/// the way GGRS does it on every rollback
+fn report_rollback_types(world: &mut World) {
+    let registry = TypeRegistry::default().register_rollback_types();
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, With<Rollback>>()
+        .iter(world)
+        .collect();
+
+    println!(
+        "  {:<16} {:>9} {:>12} {:>12}",
+        "Rollback type", "Instances", "Save", "Restore"
+    );
+    for registration in registry.iter() {
+        let (instances, save, restore) =
+            if let Some(reflect_component) = registration.data::<ReflectComponent>() {
+                let start = Instant::now();
+                let mut saved = Vec::new();
+                for _ in 0..SNAPSHOT_ITERATIONS {
+                    saved = entities
+                        .iter()
+                        .filter_map(|entity| {
+                            reflect_component
+                                .reflect_component(world, *entity)
+                                .map(|component| (*entity, component.clone_value()))
+                        })
+                        .collect();
+                }
+                let save = start.elapsed();
+
+                let start = Instant::now();
+                for _ in 0..SNAPSHOT_ITERATIONS {
+                    for (entity, component) in &saved {
+                        reflect_component.apply_component(world, *entity, &**component);
+                    }
+                }
+                (saved.len(), save, start.elapsed())
+            } else if let Some(reflect_resource) = registration.data::<ReflectResource>() {
+                let start = Instant::now();
+                let mut saved = None;
+                for _ in 0..SNAPSHOT_ITERATIONS {
+                    saved = reflect_resource
+                        .reflect_resource(world)
+                        .map(|resource| resource.clone_value());
+                }
+                let save = start.elapsed();
+
+                let start = Instant::now();
+                for _ in 0..SNAPSHOT_ITERATIONS {
+                    if let Some(resource) = &saved {
+                        reflect_resource.apply_resource(world, &**resource);
+                    }
+                }
+                (saved.is_some() as usize, save, start.elapsed())
+            } else {
+                // neither a component nor a resource, so GGRS does not save it either
+                continue;
+            };
+        println!(
+            "  {:<16} {:>9} {:>12} {:>12}",
+            registration.short_name(),
+            instances,
+            format!("{:.2?}", save / SNAPSHOT_ITERATIONS),
+            format!("{:.2?}", restore / SNAPSHOT_ITERATIONS)
+        );
+    }
+}
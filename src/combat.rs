@@ -0,0 +1,216 @@
+use crate::actions::Actions;
+use crate::config::FPS;
+use crate::dash::Dash;
+use crate::events::{GameplayEventKind, GameplayEvents};
+use crate::player::{respawn_position, spawn_position, Player};
+use crate::position::Position;
+use crate::rng::GameRng;
+use crate::tuning::Tuning;
+use crate::weapon::Weapon;
+use crate::GameState;
+use bevy::{prelude::*, render::texture::DEFAULT_IMAGE_HANDLE};
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+
+pub struct CombatPlugin;
+
+const BULLET_SIZE: f32 = 0.2;
+
+/// This plugin handles the parts of shooting that are not simulated, like giving bullets a sprite.
+/// The simulation itself happens in the rollback schedule, see [`crate::networking::NetworkingPlugin`].
+impl Plugin for CombatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(GameState::Playing).with_system(add_bullet_sprites),
+        )
+        .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(despawn_bullets));
+    }
+}
+
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct Health {
+    pub current: u32,
+    pub max: u32,
+}
+
+impl Health {
+    pub fn full(max: u32) -> Self {
+        Self { current: max, max }
+    }
+}
+
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct Score {
+    pub kills: u32,
+    pub deaths: u32,
+}
+
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct FireCooldown {
+    pub frames_left: u32,
+}
+
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq)]
+#[reflect(Component)]
+pub struct Bullet {
+    pub owner: usize,
+    /// Units per frame
+    pub velocity: Vec2,
+    pub frames_left: u32,
+    pub damage: u32,
+}
+
+pub fn fire_bullets(
+    mut commands: Commands,
+    mut rollback_id_provider: ResMut<RollbackIdProvider>,
+    mut events: ResMut<GameplayEvents>,
+    mut rng: ResMut<GameRng>,
+    tuning: Res<Tuning>,
+    actions: Res<Vec<Actions>>,
+    mut player_query: Query<(&Position, &Player, &mut FireCooldown, &mut Weapon)>,
+) {
+    let mut players: Vec<_> = player_query.iter_mut().collect();
+    // rollback ids and random numbers have to be handed out in the same order on every peer
+    players.sort_by_key(|(_, player, _, _)| player.handle());
+
+    for (position, player, mut cooldown, mut weapon) in players {
+        if cooldown.frames_left > 0 {
+            cooldown.frames_left -= 1;
+            continue;
+        }
+        let actions = &actions[player.handle()];
+        if !actions.fire {
+            continue;
+        }
+        let direction = actions
+            .aim
+            .or(actions.player_movement)
+            // without any input, players shoot towards the center
+            .unwrap_or_else(|| -spawn_position(player.handle()).normalize());
+
+        let definition = tuning.weapon(weapon.index);
+        cooldown.frames_left = definition.fire_cooldown;
+        events.send(player.handle(), GameplayEventKind::Fire, position.0);
+        for direction in definition.projectile_directions(direction, &mut rng) {
+            commands
+                .spawn()
+                .insert(*position)
+                .insert(Bullet {
+                    owner: player.handle(),
+                    velocity: direction * definition.projectile_speed / FPS as f32,
+                    frames_left: definition.projectile_lifetime,
+                    damage: definition.damage,
+                })
+                .insert(Rollback::new(rollback_id_provider.next_id()));
+        }
+        if definition.ammo.is_some() {
+            weapon.ammo = weapon.ammo.saturating_sub(1);
+            if weapon.ammo == 0 {
+                *weapon = Weapon::default();
+            }
+        }
+    }
+}
+
+pub fn move_bullets(
+    mut commands: Commands,
+    mut bullet_query: Query<(Entity, &mut Position, &mut Bullet)>,
+) {
+    for (entity, mut position, mut bullet) in bullet_query.iter_mut() {
+        position.0 += bullet.velocity;
+        bullet.frames_left = bullet.frames_left.saturating_sub(1);
+        if bullet.frames_left == 0 {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+pub fn hit_players(
+    mut commands: Commands,
+    mut events: ResMut<GameplayEvents>,
+    mut rng: ResMut<GameRng>,
+    tuning: Res<Tuning>,
+    bullet_query: Query<(Entity, &Position, &Bullet, &Rollback), Without<Player>>,
+    mut player_query: Query<(
+        &mut Position,
+        &Player,
+        &mut Health,
+        &mut Score,
+        &mut Weapon,
+        &mut Dash,
+    )>,
+) {
+    // when two bullets hit in the same frame, the order has to be the same on every peer
+    let mut bullets: Vec<_> = bullet_query
+        .iter()
+        // bullets at the end of their lifetime are already being despawned by `move_bullets`
+        .filter(|(_, _, bullet, _)| bullet.frames_left > 0)
+        .collect();
+    bullets.sort_by_key(|(_, _, _, rollback)| rollback.id());
+
+    let mut killers = Vec::new();
+    for (entity, bullet_position, bullet, _) in bullets {
+        for (mut position, player, mut health, mut score, mut weapon, mut dash) in
+            player_query.iter_mut()
+        {
+            // bullets pass through players that are invulnerable after a dash
+            if player.handle() == bullet.owner
+                || dash.is_invulnerable()
+                || position.0.distance(bullet_position.0) > tuning.hit_radius
+            {
+                continue;
+            }
+            commands.entity(entity).despawn();
+            health.current = health.current.saturating_sub(bullet.damage);
+            events.send(player.handle(), GameplayEventKind::Hit, bullet_position.0);
+            if health.current == 0 {
+                events.send(player.handle(), GameplayEventKind::Death, position.0);
+                score.deaths += 1;
+                killers.push(bullet.owner);
+                *health = Health::full(health.max);
+                *weapon = Weapon::default();
+                *dash = Dash::default();
+                position.0 = respawn_position(player.handle(), &tuning, &mut rng);
+            }
+            break;
+        }
+    }
+
+    for (_, player, _, mut score, _, _) in player_query.iter_mut() {
+        score.kills += killers
+            .iter()
+            .filter(|killer| **killer == player.handle())
+            .count() as u32;
+    }
+}
+
+/// Bullets spawned or restored by a rollback only carry the simulated components, so we add the visuals here.
+/// Their `Transform` is added by the [`crate::position::PositionPlugin`].
+fn add_bullet_sprites(
+    mut commands: Commands,
+    bullet_query: Query<Entity, (With<Bullet>, Without<Sprite>)>,
+) {
+    for entity in bullet_query.iter() {
+        commands.entity(entity).insert_bundle(bullet_sprite());
+    }
+}
+
+pub fn bullet_sprite() -> impl Bundle {
+    (
+        Sprite {
+            custom_size: Some(Vec2::splat(BULLET_SIZE)),
+            color: Color::rgb(1.0, 0.9, 0.3),
+            ..default()
+        },
+        DEFAULT_IMAGE_HANDLE.typed::<Image>(),
+        Visibility::default(),
+    )
+}
+
+fn despawn_bullets(mut commands: Commands, bullet_query: Query<Entity, With<Bullet>>) {
+    for entity in bullet_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -1 +1,4 @@
 pub const FPS: usize = 60;
+pub const INPUT_DELAY: usize = 3;
+pub const MAX_PREDICTION_WINDOW: usize = 12;
+pub const NUM_PLAYERS: usize = 2;
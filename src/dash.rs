@@ -0,0 +1,86 @@
+use crate::actions::Actions;
+use crate::player::Player;
+use crate::tuning::Tuning;
+use crate::GameState;
+use bevy::prelude::*;
+
+pub struct DashPlugin;
+
+/// Alpha of players while they can't be hit
+const INVULNERABLE_ALPHA: f32 = 0.4;
+
+/// This plugin shows which players are invulnerable after a dash.
+/// Dashes themselves are simulated in the rollback schedule, see [`crate::networking::NetworkingPlugin`].
+impl Plugin for DashPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(GameState::Playing).with_system(show_invulnerability),
+        );
+    }
+}
+
+/// A player's dash: a short burst of speed during which bullets pass through them
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq)]
+#[reflect(Component)]
+pub struct Dash {
+    /// Normalized direction of the current dash
+    pub direction: Vec2,
+    /// Frames the current dash keeps moving the player, 0 while not dashing
+    pub frames_left: u32,
+    /// Frames until the player can dash again
+    pub cooldown_frames_left: u32,
+    /// Frames until bullets hit the player again
+    pub invulnerable_frames_left: u32,
+}
+
+impl Dash {
+    pub fn is_dashing(&self) -> bool {
+        self.frames_left > 0
+    }
+
+    pub fn is_invulnerable(&self) -> bool {
+        self.invulnerable_frames_left > 0
+    }
+}
+
+/// Rollback system counting down dashes and starting new ones.
+/// Players dash in the direction they move in, or else the one they aim in.
+pub fn start_dashes(
+    actions: Res<Vec<Actions>>,
+    tuning: Res<Tuning>,
+    mut player_query: Query<(&Player, &mut Dash)>,
+) {
+    for (player, mut dash) in player_query.iter_mut() {
+        dash.frames_left = dash.frames_left.saturating_sub(1);
+        dash.cooldown_frames_left = dash.cooldown_frames_left.saturating_sub(1);
+        dash.invulnerable_frames_left = dash.invulnerable_frames_left.saturating_sub(1);
+
+        let actions = &actions[player.handle()];
+        if !actions.dash || dash.cooldown_frames_left > 0 {
+            continue;
+        }
+        let direction = match actions.player_movement.or(actions.aim) {
+            Some(direction) => direction,
+            None => continue,
+        };
+        *dash = Dash {
+            direction,
+            frames_left: tuning.dash_duration,
+            cooldown_frames_left: tuning.dash_cooldown,
+            invulnerable_frames_left: tuning.dash_invulnerability,
+        };
+    }
+}
+
+fn show_invulnerability(mut player_query: Query<(&Dash, &mut TextureAtlasSprite)>) {
+    for (dash, mut sprite) in player_query.iter_mut() {
+        let alpha = if dash.is_invulnerable() {
+            INVULNERABLE_ALPHA
+        } else {
+            1.
+        };
+        if sprite.color.a() != alpha {
+            sprite.color.set_a(alpha);
+        }
+    }
+}
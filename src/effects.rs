@@ -0,0 +1,169 @@
+use crate::events::{GameplayEvent, GameplayEventKind, GameplayEvents, PresentedEvents};
+use crate::GameState;
+use bevy::prelude::*;
+use std::f32::consts::TAU;
+
+pub struct EffectsPlugin;
+
+/// Effects are drawn above the players
+const EFFECT_Z: f32 = 10.;
+
+/// This plugin draws visual effects like muzzle flashes and explosions for [`GameplayEvents`].
+/// The effects are purely visual and not part of the rollback state.
+/// If a rollback invalidates the event an effect was spawned for, the effect is removed again.
+impl Plugin for EffectsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PresentedEvents<Entity>>()
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(spawn_effects)
+                    .with_system(update_particles)
+                    .with_system(despawn_finished_effects),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Playing).with_system(despawn_all_effects),
+            );
+    }
+}
+
+/// Root of a single effect, its particles are children
+#[derive(Component)]
+struct Effect {
+    timer: Timer,
+}
+
+#[derive(Component)]
+struct Particle {
+    /// Units per second
+    velocity: Vec2,
+    /// Size at the start of the effect, shrinks to zero over its lifetime
+    size: f32,
+}
+
+struct EffectStyle {
+    color: Color,
+    particles: usize,
+    speed: f32,
+    size: f32,
+    seconds: f32,
+}
+
+impl EffectStyle {
+    fn of(kind: GameplayEventKind) -> Self {
+        match kind {
+            // muzzle flash
+            GameplayEventKind::Fire => Self {
+                color: Color::rgb(1.0, 0.9, 0.5),
+                particles: 4,
+                speed: 1.5,
+                size: 0.25,
+                seconds: 0.1,
+            },
+            // hit spark
+            GameplayEventKind::Hit => Self {
+                color: Color::rgb(1.0, 0.6, 0.2),
+                particles: 8,
+                speed: 4.,
+                size: 0.12,
+                seconds: 0.25,
+            },
+            // explosion
+            GameplayEventKind::Death => Self {
+                color: Color::rgb(0.9, 0.3, 0.1),
+                particles: 24,
+                speed: 3.,
+                size: 0.35,
+                seconds: 0.8,
+            },
+        }
+    }
+}
+
+fn spawn_effects(
+    mut commands: Commands,
+    events: Res<GameplayEvents>,
+    mut presented_events: ResMut<PresentedEvents<Entity>>,
+    effect_query: Query<Entity, With<Effect>>,
+) {
+    let invalidated = presented_events.update(&events, |event, position| {
+        spawn_effect(&mut commands, event, position)
+    });
+    for (_, effect) in invalidated {
+        // the effect may already have finished on its own
+        if effect_query.get(effect).is_ok() {
+            commands.entity(effect).despawn_recursive();
+        }
+    }
+}
+
+fn spawn_effect(commands: &mut Commands, event: &GameplayEvent, position: Vec2) -> Entity {
+    let style = EffectStyle::of(event.kind);
+    commands
+        .spawn_bundle((
+            Transform::from_translation(position.extend(EFFECT_Z)),
+            GlobalTransform::default(),
+        ))
+        .insert(Effect {
+            timer: Timer::from_seconds(style.seconds, false),
+        })
+        .insert(Name::new(format!("{:?} Effect", event.kind)))
+        .with_children(|parent| {
+            for i in 0..style.particles {
+                // spread the particles evenly, with a slight twist per event so repeated effects don't look identical
+                let angle = (i as f32 + event.frame as f32 * 0.37) / style.particles as f32 * TAU;
+                let direction = Vec2::new(angle.cos(), angle.sin());
+                // alternate speeds so the particles don't form a perfect ring
+                let speed = style.speed * if i % 2 == 0 { 1.0 } else { 0.6 };
+                parent
+                    .spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            custom_size: Some(Vec2::splat(style.size)),
+                            color: style.color,
+                            ..default()
+                        },
+                        ..default()
+                    })
+                    .insert(Particle {
+                        velocity: direction * speed,
+                        size: style.size,
+                    });
+            }
+        })
+        .id()
+}
+
+fn update_particles(
+    time: Res<Time>,
+    mut effect_query: Query<(&mut Effect, &Children)>,
+    mut particle_query: Query<(&mut Transform, &mut Sprite, &Particle)>,
+) {
+    for (mut effect, children) in effect_query.iter_mut() {
+        effect.timer.tick(time.delta());
+        let remaining = effect.timer.percent_left();
+        for child in children.iter() {
+            if let Ok((mut transform, mut sprite, particle)) = particle_query.get_mut(*child) {
+                transform.translation += (particle.velocity * time.delta_seconds()).extend(0.);
+                sprite.custom_size = Some(Vec2::splat(particle.size * remaining));
+            }
+        }
+    }
+}
+
+fn despawn_finished_effects(mut commands: Commands, effect_query: Query<(Entity, &Effect)>) {
+    for (entity, effect) in effect_query.iter() {
+        if effect.timer.finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+fn despawn_all_effects(
+    mut commands: Commands,
+    mut presented_events: ResMut<PresentedEvents<Entity>>,
+    effect_query: Query<Entity, With<Effect>>,
+) {
+    presented_events.clear();
+    for entity in effect_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -0,0 +1,126 @@
+use crate::config::MAX_PREDICTION_WINDOW;
+use bevy::math::Vec2;
+use bevy::utils::HashMap;
+
+/// How many frames events are remembered.
+/// A rollback never goes back further than the prediction window, so older events can't be invalidated anymore.
+const EVENT_HISTORY: u32 = MAX_PREDICTION_WINDOW as u32 * 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GameplayEventKind {
+    Fire,
+    Hit,
+    Death,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GameplayEvent {
+    pub frame: u32,
+    /// The player that fired, was hit or died
+    pub player: usize,
+    pub kind: GameplayEventKind,
+}
+
+/// Things that happened in the simulation and should be presented to the player, e.g. with a sound.
+/// Unlike regular bevy events, these survive rollbacks:
+/// when a frame is resimulated, its events are discarded and sent again, so only the events of the latest simulation remain.
+/// Use [`PresentedEvents`] to find out which events are new and which were invalidated by a rollback.
+#[derive(Debug, Default)]
+pub struct GameplayEvents {
+    frame: u32,
+    /// The position is not part of the event's identity,
+    /// so an event that happens at a slightly corrected position after a rollback is not presented twice.
+    events: HashMap<GameplayEvent, Vec2>,
+    /// Counts the calls to [`GameplayEvents::reset`]
+    resets: u32,
+}
+
+impl GameplayEvents {
+    /// Must be called at the start of every simulated frame, including resimulated ones
+    pub fn start_frame(&mut self, frame: u32) {
+        self.frame = frame;
+        self.events
+            .retain(|event, _| event.frame < frame && event.frame + EVENT_HISTORY >= frame);
+    }
+
+    pub fn send(&mut self, player: usize, kind: GameplayEventKind, position: Vec2) {
+        let event = GameplayEvent {
+            frame: self.frame,
+            player,
+            kind,
+        };
+        self.events.insert(event, position);
+    }
+
+    /// Discards all events without resimulating their frames, e.g. after seeking in a replay.
+    /// Their presentations are undone, and events of the frames that were skipped over are never presented.
+    pub fn reset(&mut self) {
+        self.events.clear();
+        self.resets = self.resets.wrapping_add(1);
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&GameplayEvent, &Vec2)> {
+        self.events.iter()
+    }
+}
+
+/// Keeps track of how [`GameplayEvents`] were presented, e.g. which sound was played for them
+pub struct PresentedEvents<T> {
+    presented: HashMap<GameplayEvent, T>,
+    /// [`GameplayEvents::resets`] when the events were last presented
+    resets: u32,
+}
+
+impl<T> Default for PresentedEvents<T> {
+    fn default() -> Self {
+        Self {
+            presented: HashMap::default(),
+            resets: 0,
+        }
+    }
+}
+
+impl<T> PresentedEvents<T> {
+    /// Calls `present` for every event that has not been presented yet.
+    /// Returns the presentations of events that were invalidated by a rollback, so they can be undone.
+    pub fn update(
+        &mut self,
+        events: &GameplayEvents,
+        mut present: impl FnMut(&GameplayEvent, Vec2) -> T,
+    ) -> Vec<(GameplayEvent, T)> {
+        let mut invalidated = Vec::new();
+        if self.resets != events.resets {
+            self.resets = events.resets;
+            invalidated.extend(self.presented.drain());
+        }
+
+        let gone: Vec<_> = self
+            .presented
+            .keys()
+            .filter(|event| !events.events.contains_key(event))
+            .copied()
+            .collect();
+        for event in gone {
+            let presentation = self.presented.remove(&event).unwrap();
+            // events that are simply too old are forgotten, but did happen
+            if event.frame + EVENT_HISTORY >= events.frame {
+                invalidated.push((event, presentation));
+            }
+        }
+
+        for (event, position) in events.iter() {
+            self.presented
+                .entry(*event)
+                .or_insert_with(|| present(event, *position));
+        }
+        invalidated
+    }
+
+    pub fn clear(&mut self) {
+        self.presented.clear();
+    }
+}
@@ -0,0 +1,462 @@
+use crate::combat::Score;
+use crate::config::{FPS, NUM_PLAYERS};
+use crate::loading::FontAssets;
+use crate::menu::ButtonColors;
+use crate::networking::desync::DesyncDetector;
+use crate::networking::protocol::LocalHandles;
+use crate::networking::{FrameCount, PlatformConfig};
+use crate::player::Player;
+use crate::profile::PlayerProfiles;
+use crate::replay::viewer::{watch_replay, ReplayPlayback};
+use crate::replay::{Replay, ReplayRecorder};
+use crate::rng::MatchSeed;
+use crate::round::Round;
+use crate::storage;
+use crate::tuning::Tuning;
+use crate::GameState;
+use bevy::{log, prelude::*};
+use ggrs::P2PSession;
+use serde::{Deserialize, Serialize};
+
+pub struct HistoryPlugin;
+
+const HISTORY_KEY: &str = "history.ron";
+/// Older matches are forgotten, together with their replays
+const MAX_HISTORY: usize = 50;
+/// Matches shown on the history screen
+const SHOWN_MATCHES: usize = 10;
+/// There is only a single map so far
+const MAP_NAME: &str = "Arena";
+/// Seconds between two ping measurements
+const PING_INTERVAL: f32 = 1.0;
+
+/// This plugin records the result of every match in the local [`MatchHistory`] and draws the history screen.
+/// The history screen can be opened from the main menu.
+impl Plugin for HistoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(MatchHistory::load())
+            .init_resource::<PingSamples>()
+            .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(reset_ping))
+            .add_system_set(SystemSet::on_update(GameState::Playing).with_system(sample_ping))
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(record_match))
+            .add_system_set(SystemSet::on_exit(GameState::Menu).with_system(close_history_menu))
+            .add_system(click_history_buttons);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchResult {
+    /// Seconds since the unix epoch
+    pub played_at: u64,
+    pub players: Vec<PlayerResult>,
+    pub rounds: u32,
+    pub frames: u32,
+    pub map: String,
+    /// Average ping to our peers in milliseconds, `None` in local matches
+    pub average_ping: Option<u32>,
+    /// Whether a peer's simulation diverged from ours, see [`DesyncDetector`]
+    pub desynced: bool,
+    /// Storage key of the replay of this match, if it could be saved
+    pub replay: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerResult {
+    pub name: String,
+    pub kills: u32,
+    pub deaths: u32,
+}
+
+impl MatchResult {
+    pub fn seconds(&self) -> u32 {
+        self.frames / FPS as u32
+    }
+
+    /// Name of the player with the most kills, `None` on a draw
+    pub fn winner(&self) -> Option<&str> {
+        let most_kills = self.players.iter().map(|player| player.kills).max()?;
+        let mut best = self
+            .players
+            .iter()
+            .filter(|player| player.kills == most_kills);
+        match (best.next(), best.next()) {
+            (Some(winner), None) => Some(&winner.name),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        let scores: Vec<_> = self
+            .players
+            .iter()
+            .map(|player| format!("{} {}/{}", player.name, player.kills, player.deaths))
+            .collect();
+        let mut description = format!(
+            "{}  {}  {}:{:02} on {}",
+            format_date(self.played_at),
+            scores.join(" vs "),
+            self.seconds() / 60,
+            self.seconds() % 60,
+            self.map
+        );
+        match self.winner() {
+            Some(winner) => description += &format!("  {} won", winner),
+            None => description += "  Draw",
+        }
+        if let Some(ping) = self.average_ping {
+            description += &format!("  {}ms", ping);
+        }
+        if self.desynced {
+            description += "  DESYNC";
+        }
+        if self.replay.is_some() {
+            description += "  [replay]";
+        }
+        description
+    }
+}
+
+/// Results of the latest matches, oldest first
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MatchHistory(pub Vec<MatchResult>);
+
+impl MatchHistory {
+    pub fn load() -> Self {
+        let history = match storage::read(HISTORY_KEY) {
+            Some(history) => history,
+            None => return default(),
+        };
+        ron::from_str(&history).unwrap_or_else(|error| {
+            log::warn!("Failed to read match history: {}", error);
+            default()
+        })
+    }
+
+    fn save(&self) {
+        let result = ron::ser::to_string_pretty(self, default())
+            .map_err(|error| error.to_string())
+            .and_then(|history| storage::write(HISTORY_KEY, &history));
+        if let Err(error) = result {
+            log::error!("Failed to save match history: {}", error);
+        }
+    }
+
+    fn add(&mut self, result: MatchResult) {
+        self.0.push(result);
+        let excess = self.0.len().saturating_sub(MAX_HISTORY);
+        for forgotten in self.0.drain(..excess) {
+            if let Some(replay) = forgotten.replay {
+                if let Err(error) = storage::remove(&replay) {
+                    log::warn!("Failed to remove old replay {}: {}", replay, error);
+                }
+            }
+        }
+        self.save();
+    }
+}
+
+#[derive(Default)]
+struct PingSamples {
+    total: u128,
+    count: u32,
+    timer: Timer,
+}
+
+fn reset_ping(mut samples: ResMut<PingSamples>) {
+    *samples = PingSamples {
+        timer: Timer::from_seconds(PING_INTERVAL, true),
+        ..default()
+    };
+}
+
+fn sample_ping(
+    time: Res<Time>,
+    session: Option<Res<P2PSession<PlatformConfig>>>,
+    local_handles: Option<Res<LocalHandles>>,
+    mut samples: ResMut<PingSamples>,
+) {
+    if !samples.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let (session, local_handles) = match (session, local_handles) {
+        (Some(session), Some(local_handles)) => (session, local_handles),
+        _ => return,
+    };
+    for handle in (0..NUM_PLAYERS).filter(|handle| !local_handles.handles.contains(handle)) {
+        if let Ok(stats) = session.network_stats(handle) {
+            samples.total += stats.ping;
+            samples.count += 1;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn record_match(
+    frame_count: Res<FrameCount>,
+    round: Res<Round>,
+    tuning: Res<Tuning>,
+    seed: Res<MatchSeed>,
+    profiles: Option<Res<PlayerProfiles>>,
+    local_handles: Option<Res<LocalHandles>>,
+    recorder: Res<ReplayRecorder>,
+    desync_detector: Res<DesyncDetector>,
+    samples: Res<PingSamples>,
+    replay_playback: Option<Res<ReplayPlayback>>,
+    mut history: ResMut<MatchHistory>,
+    player_query: Query<(&Player, &Name, &Score)>,
+) {
+    // watching a replay is not a match of its own
+    if frame_count.frame == 0 || replay_playback.is_some() {
+        return;
+    }
+    let played_at = unix_time();
+    let mut players: Vec<_> = player_query.iter().collect();
+    players.sort_by_key(|(player, ..)| player.handle());
+    let profiles = profiles
+        .map(|profiles| profiles.clone())
+        .unwrap_or_default();
+    let player_profiles = players
+        .iter()
+        .map(|(player, ..)| profiles.get(player.handle()))
+        .collect();
+    // every peer records the match, possibly on the same machine, and a rematch may end within the same second
+    let local_handle = local_handles
+        .and_then(|local_handles| local_handles.handles.first().copied())
+        .unwrap_or_default();
+    let replay = recorder.save(
+        &format!(
+            "replays/{}-{:016x}-{}.replay.ron",
+            played_at, seed.0, local_handle
+        ),
+        &tuning,
+        *seed,
+        player_profiles,
+    );
+
+    let result = MatchResult {
+        played_at,
+        players: players
+            .iter()
+            .map(|(_, name, score)| PlayerResult {
+                name: name.to_string(),
+                kills: score.kills,
+                deaths: score.deaths,
+            })
+            .collect(),
+        rounds: round.number,
+        frames: frame_count.frame,
+        map: MAP_NAME.to_string(),
+        average_ping: (samples.count > 0).then(|| (samples.total / samples.count as u128) as u32),
+        desynced: desync_detector.desync_frame.is_some(),
+        replay,
+    };
+    log::info!("Match finished: {}", result.describe());
+    history.add(result);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn unix_time() -> u64 {
+    (js_sys::Date::now() / 1000.) as u64
+}
+
+/// Formats seconds since the unix epoch as UTC date and time
+fn format_date(unix_time: u64) -> String {
+    let days = (unix_time / 86400) as i64;
+    let seconds = unix_time % 86400;
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+#[derive(Component)]
+pub struct HistoryMenu;
+
+#[derive(Component, Clone)]
+enum HistoryButton {
+    /// Watches the replay stored under this key
+    Watch(String),
+    Back,
+}
+
+/// Draws the latest matches on top of everything else
+pub fn open_history_menu(
+    commands: &mut Commands,
+    font_assets: &FontAssets,
+    button_colors: &ButtonColors,
+    history: &MatchHistory,
+) {
+    let text_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 20.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                position_type: PositionType::Absolute,
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: Color::rgb(0.1, 0.1, 0.1).into(),
+            ..Default::default()
+        })
+        .insert(HistoryMenu)
+        .insert(Name::new("History Menu"))
+        .with_children(|parent| {
+            if history.0.is_empty() {
+                parent.spawn_bundle(TextBundle {
+                    text: Text::with_section(
+                        "No matches played yet",
+                        text_style.clone(),
+                        Default::default(),
+                    ),
+                    ..Default::default()
+                });
+            }
+            for result in history.0.iter().rev().take(SHOWN_MATCHES) {
+                parent
+                    .spawn_bundle(NodeBundle {
+                        style: Style {
+                            margin: Rect::all(Val::Px(4.0)),
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        color: Color::NONE.into(),
+                        ..Default::default()
+                    })
+                    .with_children(|parent| {
+                        parent.spawn_bundle(TextBundle {
+                            text: Text::with_section(
+                                result.describe(),
+                                text_style.clone(),
+                                Default::default(),
+                            ),
+                            ..Default::default()
+                        });
+                        if let Some(replay) = &result.replay {
+                            spawn_button(
+                                parent,
+                                HistoryButton::Watch(replay.clone()),
+                                "Watch",
+                                Size::new(Val::Px(80.0), Val::Px(28.0)),
+                                &text_style,
+                                button_colors,
+                            );
+                        }
+                    });
+            }
+            spawn_button(
+                parent,
+                HistoryButton::Back,
+                "Back",
+                Size::new(Val::Px(120.0), Val::Px(40.0)),
+                &TextStyle {
+                    font_size: 30.0,
+                    ..text_style.clone()
+                },
+                button_colors,
+            );
+        });
+}
+
+fn spawn_button(
+    parent: &mut ChildBuilder,
+    button: HistoryButton,
+    label: &str,
+    size: Size<Val>,
+    text_style: &TextStyle,
+    button_colors: &ButtonColors,
+) {
+    parent
+        .spawn_bundle(ButtonBundle {
+            style: Style {
+                size,
+                margin: Rect::all(Val::Px(10.0)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: button_colors.normal,
+            ..Default::default()
+        })
+        .insert(button)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(label, text_style.clone(), Default::default()),
+                ..Default::default()
+            });
+        });
+}
+
+#[allow(clippy::type_complexity)]
+fn click_history_buttons(
+    mut commands: Commands,
+    button_colors: Res<ButtonColors>,
+    mut state: ResMut<State<GameState>>,
+    menu_query: Query<Entity, With<HistoryMenu>>,
+    mut interaction_query: Query<
+        (&HistoryButton, &Interaction, &mut UiColor),
+        Changed<Interaction>,
+    >,
+) {
+    for (button, interaction, mut color) in interaction_query.iter_mut() {
+        match *interaction {
+            Interaction::Clicked => match button {
+                HistoryButton::Watch(key) => match Replay::load(key) {
+                    Some(replay) => {
+                        watch_replay(&mut commands, replay);
+                        state.set(GameState::Playing).unwrap();
+                    }
+                    None => log::warn!("Replay {} can't be watched", key),
+                },
+                HistoryButton::Back => {
+                    for menu in menu_query.iter() {
+                        commands.entity(menu).despawn_recursive();
+                    }
+                }
+            },
+            Interaction::Hovered => {
+                *color = button_colors.hovered;
+            }
+            Interaction::None => {
+                *color = button_colors.normal;
+            }
+        }
+    }
+}
+
+fn close_history_menu(mut commands: Commands, menu_query: Query<Entity, With<HistoryMenu>>) {
+    for menu in menu_query.iter() {
+        commands.entity(menu).despawn_recursive();
+    }
+}
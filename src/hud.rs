@@ -0,0 +1,348 @@
+use crate::combat::{Health, Score};
+use crate::config::NUM_PLAYERS;
+use crate::loading::FontAssets;
+use crate::networking::desync::DesyncDetector;
+use crate::networking::protocol::LocalHandles;
+use crate::networking::sync::SyncStatus;
+use crate::networking::PlatformConfig;
+use crate::player::Player;
+use crate::round::Round;
+use crate::tuning::Tuning;
+use crate::weapon::Weapon;
+use crate::GameState;
+use bevy::prelude::*;
+use ggrs::P2PSession;
+
+pub struct HudPlugin;
+
+const HEALTH_BAR_WIDTH: f32 = 0.8;
+const HEALTH_BAR_HEIGHT: f32 = 0.1;
+/// Offset of the health bar from the center of its player
+const HEALTH_BAR_OFFSET: f32 = 0.7;
+/// World units per pixel of the main camera, used to scale the name labels
+const WORLD_UNITS_PER_PIXEL: f32 = 1. / 50.;
+/// Pings below these thresholds (in milliseconds) show three or two bars
+const PING_THRESHOLDS: [u128; 2] = [80, 150];
+const CONNECTION_BARS: usize = 3;
+
+/// This plugin draws the in-game HUD: health bars and names above the players,
+/// a scoreboard with everyone's weapon, the round timer, the connection quality to our peers and why we are waiting for them.
+/// Everything shown is read from the rollback state, but the HUD itself is updated outside of the rollback schedule.
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_enter(GameState::Playing).with_system(spawn_hud))
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(add_player_labels)
+                    .with_system(update_health_bars)
+                    .with_system(update_scoreboard)
+                    .with_system(update_round_timer)
+                    .with_system(update_connection_quality)
+                    .with_system(update_waiting_text),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(despawn_hud));
+    }
+}
+
+#[derive(Component)]
+struct HudRoot;
+
+#[derive(Component)]
+struct HealthBarFill;
+
+#[derive(Component)]
+struct ScoreText {
+    handle: usize,
+}
+
+#[derive(Component)]
+struct RoundTimerText;
+
+#[derive(Component)]
+struct WaitingText;
+
+#[derive(Component)]
+struct ConnectionIndicator {
+    handle: usize,
+}
+
+#[derive(Component)]
+struct ConnectionBar {
+    level: usize,
+}
+
+fn spawn_hud(mut commands: Commands, font_assets: Res<FontAssets>) {
+    let text_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 24.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Px(40.0)),
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(0.0),
+                    ..default()
+                },
+                justify_content: JustifyContent::SpaceBetween,
+                align_items: AlignItems::Center,
+                padding: Rect::all(Val::Px(10.0)),
+                ..default()
+            },
+            color: Color::NONE.into(),
+            ..default()
+        })
+        .insert(HudRoot)
+        .insert(Name::new("HUD"))
+        .with_children(|parent| {
+            for handle in 0..NUM_PLAYERS {
+                // the round timer sits between the first and the second half of the players
+                if handle == (NUM_PLAYERS + 1) / 2 {
+                    parent
+                        .spawn_bundle(TextBundle {
+                            text: Text::with_section("", text_style.clone(), default()),
+                            ..default()
+                        })
+                        .insert(RoundTimerText)
+                        .insert(Name::new("Round Timer"));
+                }
+                spawn_scoreboard_entry(parent, handle, &text_style);
+            }
+        });
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(60.0),
+                    left: Val::Px(10.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::with_section("", text_style, default()),
+            ..default()
+        })
+        .insert(HudRoot)
+        .insert(WaitingText)
+        .insert(Name::new("Waiting Text"));
+}
+
+fn spawn_scoreboard_entry(parent: &mut ChildBuilder, handle: usize, text_style: &TextStyle) {
+    parent
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                align_items: AlignItems::FlexEnd,
+                ..default()
+            },
+            color: Color::NONE.into(),
+            ..default()
+        })
+        .insert(Name::new(format!("Scoreboard Entry {}", handle)))
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle {
+                    text: Text::with_section("", text_style.clone(), default()),
+                    ..default()
+                })
+                .insert(ScoreText { handle });
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        margin: Rect {
+                            left: Val::Px(8.0),
+                            ..default()
+                        },
+                        align_items: AlignItems::FlexEnd,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .insert(ConnectionIndicator { handle })
+                .with_children(|parent| {
+                    for level in 1..=CONNECTION_BARS {
+                        parent
+                            .spawn_bundle(NodeBundle {
+                                style: Style {
+                                    size: Size::new(
+                                        Val::Px(4.0),
+                                        Val::Px(4.0 * level as f32 + 2.0),
+                                    ),
+                                    margin: Rect {
+                                        left: Val::Px(2.0),
+                                        bottom: Val::Px(4.0),
+                                        ..default()
+                                    },
+                                    ..default()
+                                },
+                                ..default()
+                            })
+                            .insert(ConnectionBar { level });
+                    }
+                });
+        });
+}
+
+/// Adds a health bar and a name label to players spawned by the [`crate::player::PlayerPlugin`]
+fn add_player_labels(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    player_query: Query<(Entity, &Name), Added<Player>>,
+) {
+    for (entity, name) in player_query.iter() {
+        commands.entity(entity).with_children(|parent| {
+            parent.spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::new(HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT)),
+                    color: Color::rgba(0.1, 0.1, 0.1, 0.8),
+                    ..default()
+                },
+                transform: Transform::from_xyz(0., HEALTH_BAR_OFFSET, 1.),
+                ..default()
+            });
+            parent
+                .spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        custom_size: Some(Vec2::new(HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT)),
+                        color: Color::rgb(0.2, 0.8, 0.2),
+                        ..default()
+                    },
+                    transform: Transform::from_xyz(0., HEALTH_BAR_OFFSET, 2.),
+                    ..default()
+                })
+                .insert(HealthBarFill);
+            parent.spawn_bundle(Text2dBundle {
+                text: Text::with_section(
+                    name.as_str(),
+                    TextStyle {
+                        font: font_assets.fira_sans.clone(),
+                        font_size: 16.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                    TextAlignment {
+                        vertical: VerticalAlign::Bottom,
+                        horizontal: HorizontalAlign::Center,
+                    },
+                ),
+                transform: Transform::from_xyz(0., HEALTH_BAR_OFFSET + HEALTH_BAR_HEIGHT, 1.)
+                    .with_scale(Vec3::splat(WORLD_UNITS_PER_PIXEL)),
+                ..default()
+            });
+        });
+    }
+}
+
+fn update_health_bars(
+    player_query: Query<&Health, With<Player>>,
+    mut bar_query: Query<(&Parent, &mut Transform, &mut Sprite), With<HealthBarFill>>,
+) {
+    for (parent, mut transform, mut sprite) in bar_query.iter_mut() {
+        let health = match player_query.get(parent.0) {
+            Ok(health) => health,
+            Err(_) => continue,
+        };
+        let fraction = health.current as f32 / health.max as f32;
+        // keep the bar aligned to the left while it shrinks
+        transform.translation.x = -(1. - fraction) * HEALTH_BAR_WIDTH / 2.;
+        sprite.custom_size = Some(Vec2::new(fraction * HEALTH_BAR_WIDTH, HEALTH_BAR_HEIGHT));
+    }
+}
+
+fn update_scoreboard(
+    tuning: Res<Tuning>,
+    player_query: Query<(&Player, &Name, &Score, &Weapon, &TextureAtlasSprite)>,
+    mut text_query: Query<(&ScoreText, &mut Text)>,
+) {
+    for (score_text, mut text) in text_query.iter_mut() {
+        if let Some((_, name, score, weapon, sprite)) = player_query
+            .iter()
+            .find(|(player, _, _, _, _)| player.handle() == score_text.handle)
+        {
+            let definition = tuning.weapon(weapon.index);
+            let weapon = match definition.ammo {
+                Some(_) => format!("{} ({})", definition.name, weapon.ammo),
+                None => definition.name.clone(),
+            };
+            text.sections[0].value =
+                format!("{}  {} / {}  {}", name, score.kills, score.deaths, weapon);
+            text.sections[0].style.color = sprite.color;
+        }
+    }
+}
+
+fn update_round_timer(round: Res<Round>, mut text_query: Query<&mut Text, With<RoundTimerText>>) {
+    let seconds = round.seconds_left();
+    for mut text in text_query.iter_mut() {
+        text.sections[0].value = format!(
+            "Round {}  {}:{:02}",
+            round.number,
+            seconds / 60,
+            seconds % 60
+        );
+    }
+}
+
+/// Shows between one and three bars depending on the ping to each remote player.
+/// No bars are lit while we don't know the ping yet, and local players have no indicator at all.
+fn update_connection_quality(
+    session: Option<Res<P2PSession<PlatformConfig>>>,
+    local_handles: Res<LocalHandles>,
+    indicator_query: Query<(&ConnectionIndicator, &Children)>,
+    mut bar_query: Query<(&ConnectionBar, &mut UiColor)>,
+) {
+    for (indicator, children) in indicator_query.iter() {
+        let stats = match &session {
+            Some(session) if !local_handles.handles.contains(&indicator.handle) => {
+                Some(session.network_stats(indicator.handle))
+            }
+            _ => None,
+        };
+        let (level, color) = match stats {
+            None => (0, Color::NONE),
+            Some(Ok(stats)) if stats.ping < PING_THRESHOLDS[0] => (3, Color::rgb(0.2, 0.8, 0.2)),
+            Some(Ok(stats)) if stats.ping < PING_THRESHOLDS[1] => (2, Color::rgb(0.9, 0.8, 0.2)),
+            Some(Ok(_)) => (1, Color::rgb(0.9, 0.2, 0.2)),
+            Some(Err(_)) => (0, Color::rgb(0.5, 0.5, 0.5)),
+        };
+        let unlit = if stats.is_some() {
+            Color::rgba(0.5, 0.5, 0.5, 0.5)
+        } else {
+            Color::NONE
+        };
+        for child in children.iter() {
+            if let Ok((bar, mut bar_color)) = bar_query.get_mut(*child) {
+                *bar_color = if bar.level <= level { color } else { unlit }.into();
+            }
+        }
+    }
+}
+
+/// Tells the player why the match hitches, e.g. while waiting for a peer's inputs
+fn update_waiting_text(
+    status: Res<SyncStatus>,
+    desync_detector: Res<DesyncDetector>,
+    mut text_query: Query<&mut Text, With<WaitingText>>,
+) {
+    let message = match (&status.waiting, desync_detector.desync_frame) {
+        (Some(waiting), _) => waiting.describe(),
+        (None, Some(frame)) => format!("Desync detected at frame {}", frame),
+        (None, None) if status.is_skipping_frames() => {
+            format!("{} frames ahead of peer, waiting", status.frames_ahead)
+        }
+        (None, None) => String::new(),
+    };
+    for mut text in text_query.iter_mut() {
+        if text.sections[0].value != message {
+            text.sections[0].value = message.clone();
+        }
+    }
+}
+
+fn despawn_hud(mut commands: Commands, hud_query: Query<Entity, With<HudRoot>>) {
+    for entity in hud_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
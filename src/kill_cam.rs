@@ -0,0 +1,524 @@
+use crate::actions::Actions;
+use crate::combat::{bullet_sprite, Bullet};
+use crate::config::{FPS, NUM_PLAYERS};
+use crate::events::{GameplayEvent, GameplayEventKind, GameplayEvents, PresentedEvents};
+use crate::loading::FontAssets;
+use crate::networking::budget::RollbackTimings;
+use crate::networking::desync::DesyncDetector;
+use crate::networking::error::NetworkingError;
+use crate::networking::protocol::{InputProtocol, LocalHandles};
+use crate::networking::{rollback_schedule, FrameCount, RollbackStatus};
+use crate::pause::Pause;
+use crate::player::{MainCamera, Player};
+use crate::position::Position;
+use crate::profile::PlayerProfiles;
+use crate::replay::snapshot::{RollbackTypes, WorldSnapshot};
+use crate::replay::viewer::ReplayPlayback;
+use crate::replay::{recorded_inputs, ReplayRecorder};
+use crate::rng::GameRng;
+use crate::round::{Round, ROUND_DURATION};
+use crate::settings::Settings;
+use crate::tuning::Tuning;
+use crate::GameState;
+use bevy::{ecs::event::Events, log, prelude::*, transform::TransformSystem, utils::HashMap};
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use ggrs::InputStatus;
+use std::collections::VecDeque;
+
+pub struct KillCamPlugin;
+
+/// Seconds before a kill that are shown again
+const KILL_CAM_SECONDS: u32 = 3;
+/// Frames between two snapshots in the [`KillCamBuffer`]
+const SNAPSHOT_INTERVAL: u32 = FPS as u32 / 2;
+/// Frames the [`KillCamBuffer`] keeps. Besides the frames that are shown,
+/// this covers the frames between the snapshot a kill-cam starts at and the shown ones,
+/// as well as the frames simulated while waiting for the kill to be confirmed.
+const BUFFER_FRAMES: u32 = (KILL_CAM_SECONDS + 2) * FPS as u32;
+/// The last frames before the kill are shown at half speed
+const SLOW_MOTION_FRAMES: usize = FPS / 2;
+/// Seconds the kill-cam stays on the kill before the match is shown again
+const LINGER_SECONDS: f32 = 1.;
+/// Scale of the camera following the victim, relative to the normal camera
+const ZOOM: f32 = 0.7;
+
+/// This plugin shows the last seconds before a local player died, or before a round ended, once more.
+/// The kill-cam is simulated in a world of its own from the confirmed inputs in the [`KillCamBuffer`],
+/// so online, the match keeps running in the background and is in sync with our peers when the kill-cam ends.
+/// The kill-cam only changes what is shown: local players keep sending their inputs while it plays.
+/// In local matches, the match waits for the kill-cam instead.
+impl Plugin for KillCamPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<KillCamQueue>()
+            .add_system_set(
+                SystemSet::on_enter(GameState::Playing).with_system(reset_kill_cam_queue),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(queue_kill_cams.label(KillCamSystems::Queue))
+                    .with_system(
+                        start_kill_cam
+                            .label(KillCamSystems::Start)
+                            .after(KillCamSystems::Queue),
+                    )
+                    .with_system(play_kill_cam.after(KillCamSystems::Start)),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                show_kill_cam.before(TransformSystem::TransformPropagate),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(end_kill_cam));
+    }
+}
+
+#[derive(SystemLabel, Clone, Hash, Debug, Eq, PartialEq)]
+enum KillCamSystems {
+    Queue,
+    Start,
+}
+
+/// The recent past of the match: snapshots and the inputs since the oldest of them. Not rolled back.
+/// Resimulated frames replace what was stored for them, so once a frame is confirmed, its entries are final.
+#[derive(Default)]
+pub struct KillCamBuffer {
+    types: RollbackTypes,
+    /// Snapshot of every [`SNAPSHOT_INTERVAL`]th frame, oldest first
+    snapshots: VecDeque<(u32, WorldSnapshot)>,
+    /// Inputs of every player by frame, oldest first
+    inputs: VecDeque<(u32, Vec<InputProtocol>)>,
+}
+
+impl KillCamBuffer {
+    /// A world at a snapshot at least [`KILL_CAM_SECONDS`] before `end_frame`,
+    /// and the inputs of all frames from there to `end_frame`
+    fn replay_until(
+        &self,
+        end_frame: u32,
+        tuning: &Tuning,
+    ) -> Option<(World, VecDeque<Vec<InputProtocol>>)> {
+        let start_frame = end_frame.saturating_sub(KILL_CAM_SECONDS * FPS as u32);
+        let (snapshot_frame, snapshot) = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|(frame, _)| *frame <= start_frame)
+            .or_else(|| self.snapshots.front())?;
+        if *snapshot_frame > end_frame {
+            return None;
+        }
+        let inputs: VecDeque<_> = self
+            .inputs
+            .iter()
+            .filter(|(frame, _)| *frame > *snapshot_frame && *frame <= end_frame)
+            .map(|(_, inputs)| inputs.clone())
+            .collect();
+        if inputs.len() as u32 != end_frame - snapshot_frame {
+            return None;
+        }
+
+        let mut world = simulation_world(tuning);
+        snapshot.restore(&mut world, &self.types);
+        // bullets fired during the kill-cam have to be ordered after the restored ones, as they were in the match
+        let last_id = world
+            .query::<&Rollback>()
+            .iter(&world)
+            .map(Rollback::id)
+            .max();
+        if let Some(last_id) = last_id {
+            let mut rollback_id_provider = world.resource_mut::<RollbackIdProvider>();
+            while rollback_id_provider.next_id() < last_id {}
+        }
+        Some((world, inputs))
+    }
+}
+
+/// Inserts `value` for `frame`, replacing what was stored for it and all later frames
+fn insert<T>(buffer: &mut VecDeque<(u32, T)>, frame: u32, value: T) {
+    while matches!(buffer.back(), Some((stored, _)) if *stored >= frame) {
+        buffer.pop_back();
+    }
+    buffer.push_back((frame, value));
+    while matches!(buffer.front(), Some((stored, _)) if *stored + BUFFER_FRAMES < frame) {
+        buffer.pop_front();
+    }
+}
+
+pub fn reset_kill_cam_buffer(mut buffer: ResMut<KillCamBuffer>) {
+    *buffer = default();
+}
+
+/// Rollback system keeping the inputs of the recent frames
+pub fn buffer_inputs(
+    frame_count: Res<FrameCount>,
+    inputs: Res<Vec<(InputProtocol, InputStatus)>>,
+    mut buffer: ResMut<KillCamBuffer>,
+) {
+    insert(
+        &mut buffer.inputs,
+        frame_count.frame,
+        recorded_inputs(&inputs),
+    );
+}
+
+/// Exclusive rollback system, runs at the end of every frame and takes a snapshot every [`SNAPSHOT_INTERVAL`] frames
+pub fn buffer_snapshot(world: &mut World) {
+    let frame = world.resource::<FrameCount>().frame;
+    if frame % SNAPSHOT_INTERVAL != 0 {
+        return;
+    }
+    world.resource_scope(|world, mut buffer: Mut<KillCamBuffer>| {
+        // while the simulation is frozen, the same frame is not simulated again
+        let resimulating = world.resource::<RollbackStatus>().resimulating;
+        if matches!(buffer.snapshots.back(), Some((stored, _)) if *stored == frame) && !resimulating
+        {
+            return;
+        }
+        let snapshot = WorldSnapshot::take(world, &buffer.types);
+        insert(&mut buffer.snapshots, frame, snapshot);
+    });
+}
+
+/// A world the rollback schedule can run in, with all resources it needs but without any entities
+fn simulation_world(tuning: &Tuning) -> World {
+    let mut world = World::new();
+    world.insert_resource(State::new(GameState::Playing));
+    world.insert_resource(tuning.clone());
+    world.init_resource::<Pause>();
+    world.init_resource::<Vec<Actions>>();
+    world.init_resource::<Round>();
+    world.init_resource::<FrameCount>();
+    world.init_resource::<GameRng>();
+    world.init_resource::<RollbackStatus>();
+    world.init_resource::<RollbackTimings>();
+    world.init_resource::<RollbackIdProvider>();
+    world.init_resource::<DesyncDetector>();
+    world.init_resource::<ReplayRecorder>();
+    world.init_resource::<KillCamBuffer>();
+    world.init_resource::<GameplayEvents>();
+    world.init_resource::<Events<NetworkingError>>();
+    world
+}
+
+/// A kill-cam that waits for the frames it shows to be confirmed
+#[derive(Debug, Clone, Copy)]
+struct PendingKillCam {
+    /// Last frame shown
+    frame: u32,
+    /// The death the kill-cam shows, `None` at the end of a round
+    death: Option<GameplayEvent>,
+}
+
+#[derive(Default)]
+struct KillCamQueue {
+    pending: Option<PendingKillCam>,
+    presented: PresentedEvents<()>,
+    round: u32,
+}
+
+fn reset_kill_cam_queue(mut queue: ResMut<KillCamQueue>) {
+    *queue = KillCamQueue {
+        round: Round::default().number,
+        ..default()
+    };
+}
+
+/// Queues a kill-cam when a local player dies or a round ends
+fn queue_kill_cams(
+    settings: Res<Settings>,
+    events: Res<GameplayEvents>,
+    round: Res<Round>,
+    local_handles: Option<Res<LocalHandles>>,
+    replay: Option<Res<ReplayPlayback>>,
+    mut queue: ResMut<KillCamQueue>,
+) {
+    let mut deaths = Vec::new();
+    let invalidated = queue.presented.update(&events, |event, _| {
+        if event.kind == GameplayEventKind::Death {
+            deaths.push(*event);
+        }
+    });
+    // a death that was rolled back never happened
+    if let Some(death) = queue.pending.and_then(|pending| pending.death) {
+        if invalidated.iter().any(|(event, _)| *event == death) {
+            queue.pending = None;
+        }
+    }
+    if !settings.kill_cam || replay.is_some() {
+        queue.pending = None;
+        return;
+    }
+
+    let is_local = |handle| {
+        local_handles.as_ref().map_or(false, |local_handles| {
+            local_handles.handles.contains(&handle)
+        })
+    };
+    if let Some(death) = deaths
+        .into_iter()
+        .filter(|death| is_local(death.player))
+        .last()
+    {
+        queue.pending = Some(PendingKillCam {
+            frame: death.frame,
+            death: Some(death),
+        });
+    }
+    // rounds end after a fixed number of frames, so this is never rolled back
+    if round.number > queue.round {
+        queue.round = round.number;
+        queue.pending = Some(PendingKillCam {
+            frame: (round.number - 1) * ROUND_DURATION,
+            death: None,
+        });
+    }
+}
+
+/// The last seconds before a kill, replayed in a world of their own
+pub struct KillCam {
+    world: World,
+    schedule: Schedule,
+    /// Inputs of the frames still to be shown, starting with the next one
+    inputs: VecDeque<Vec<InputProtocol>>,
+    /// The player the camera follows, usually the victim
+    focus: Option<usize>,
+    accumulator: f32,
+    linger: Timer,
+    /// Whether the match waits for the kill-cam, which it only does in local matches
+    pub freezes_simulation: bool,
+    /// Where the camera was before the kill-cam took it over
+    camera: (Transform, f32),
+}
+
+impl KillCam {
+    /// Returns where the camera showing the match is, while the kill-cam has taken it over
+    pub fn match_camera(&self) -> (Transform, f32) {
+        self.camera
+    }
+
+    /// Returns `false` once all frames were shown
+    fn simulate_frame(&mut self) -> bool {
+        let inputs = match self.inputs.pop_front() {
+            Some(inputs) => inputs,
+            None => return false,
+        };
+        self.world
+            .insert_resource::<Vec<(InputProtocol, InputStatus)>>(
+                inputs
+                    .into_iter()
+                    .map(|input| (input, InputStatus::Confirmed))
+                    .collect(),
+            );
+        self.schedule.run(&mut self.world);
+        true
+    }
+}
+
+#[derive(Component)]
+struct KillCamUi;
+
+/// Stands in for a bullet of the kill-cam world, by rollback id
+#[derive(Component)]
+struct KillCamBullet(u32);
+
+#[allow(clippy::too_many_arguments)]
+fn start_kill_cam(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    tuning: Res<Tuning>,
+    recorder: Res<ReplayRecorder>,
+    buffer: Res<KillCamBuffer>,
+    local_handles: Option<Res<LocalHandles>>,
+    profiles: Option<Res<PlayerProfiles>>,
+    mut queue: ResMut<KillCamQueue>,
+    kill_cam: Option<Res<KillCam>>,
+    camera_query: Query<(&Transform, &OrthographicProjection), With<MainCamera>>,
+) {
+    let pending = match queue.pending {
+        Some(pending) if kill_cam.is_none() && pending.frame <= recorder.confirmed_frames() => {
+            pending
+        }
+        _ => return,
+    };
+    queue.pending = None;
+    let camera = match camera_query.get_single() {
+        Ok((transform, projection)) => (*transform, projection.scale),
+        Err(_) => return,
+    };
+    let (world, inputs) = match buffer.replay_until(pending.frame, &tuning) {
+        Some(replay) => replay,
+        None => {
+            log::warn!(
+                "Frame {} is not buffered anymore, skipping the kill-cam",
+                pending.frame
+            );
+            return;
+        }
+    };
+
+    let mut kill_cam = KillCam {
+        world,
+        schedule: rollback_schedule(),
+        inputs,
+        focus: pending.death.map(|death| death.player),
+        accumulator: 0.,
+        linger: Timer::from_seconds(LINGER_SECONDS, false),
+        freezes_simulation: local_handles.map_or(false, |local_handles| {
+            local_handles.handles.len() == NUM_PLAYERS
+        }),
+        camera,
+    };
+    // the buffer starts at a snapshot before the frames that are shown
+    while kill_cam.inputs.len() > KILL_CAM_SECONDS as usize * FPS {
+        kill_cam.simulate_frame();
+    }
+
+    let title = match pending.death {
+        Some(death) => {
+            let name = profiles
+                .map(|profiles| profiles.get(death.player).name)
+                .unwrap_or_else(|| format!("Player {}", death.player));
+            format!("Kill cam: {}", name)
+        }
+        None => "End of the round".to_string(),
+    };
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(100.0),
+                    left: Val::Px(10.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::with_section(
+                title,
+                TextStyle {
+                    font: font_assets.fira_sans.clone(),
+                    font_size: 40.0,
+                    color: Color::rgb(0.9, 0.3, 0.3),
+                },
+                default(),
+            ),
+            ..default()
+        })
+        .insert(KillCamUi)
+        .insert(Name::new("Kill Cam Text"));
+    commands.insert_resource(kill_cam);
+}
+
+fn play_kill_cam(
+    mut commands: Commands,
+    time: Res<Time>,
+    kill_cam: Option<ResMut<KillCam>>,
+    ui_query: Query<Entity, Or<(With<KillCamUi>, With<KillCamBullet>)>>,
+    mut camera_query: Query<(&mut Transform, &mut OrthographicProjection), With<MainCamera>>,
+) {
+    let mut kill_cam = match kill_cam {
+        Some(kill_cam) => kill_cam,
+        None => return,
+    };
+    let speed = if kill_cam.inputs.len() <= SLOW_MOTION_FRAMES {
+        0.5
+    } else {
+        1.
+    };
+    kill_cam.accumulator += time.delta_seconds() * speed * FPS as f32;
+    while kill_cam.accumulator >= 1. {
+        kill_cam.accumulator -= 1.;
+        if !kill_cam.simulate_frame() {
+            kill_cam.accumulator = 0.;
+        }
+    }
+    if !kill_cam.inputs.is_empty() || !kill_cam.linger.tick(time.delta()).finished() {
+        return;
+    }
+
+    if let Ok((mut transform, mut projection)) = camera_query.get_single_mut() {
+        *transform = kill_cam.camera.0;
+        projection.scale = kill_cam.camera.1;
+    }
+    end_kill_cam(commands, ui_query);
+}
+
+fn end_kill_cam(
+    mut commands: Commands,
+    ui_query: Query<Entity, Or<(With<KillCamUi>, With<KillCamBullet>)>>,
+) {
+    commands.remove_resource::<KillCam>();
+    for entity in ui_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/// Shows the kill-cam world instead of the match: players are moved to where they were back then,
+/// the bullets of the match are hidden and the ones of the kill-cam drawn instead
+#[allow(clippy::type_complexity)]
+fn show_kill_cam(
+    mut commands: Commands,
+    kill_cam: Option<ResMut<KillCam>>,
+    mut player_query: Query<(&Player, &mut Transform), Without<KillCamBullet>>,
+    mut camera_query: Query<
+        (&mut Transform, &mut OrthographicProjection),
+        (With<MainCamera>, Without<Player>, Without<KillCamBullet>),
+    >,
+    mut ghost_query: Query<
+        (Entity, &KillCamBullet, &mut Transform),
+        (Without<Player>, Without<MainCamera>),
+    >,
+    mut bullet_query: Query<&mut Visibility, With<Bullet>>,
+) {
+    for mut visibility in bullet_query.iter_mut() {
+        visibility.is_visible = kill_cam.is_none();
+    }
+    let mut kill_cam = match kill_cam {
+        Some(kill_cam) => kill_cam,
+        None => return,
+    };
+    let kill_cam = &mut *kill_cam;
+
+    let players: HashMap<usize, Vec2> = kill_cam
+        .world
+        .query::<(&Player, &Position)>()
+        .iter(&kill_cam.world)
+        .map(|(player, position)| (player.handle(), position.0))
+        .collect();
+    for (player, mut transform) in player_query.iter_mut() {
+        if let Some(position) = players.get(&player.handle()) {
+            transform.translation.x = position.x;
+            transform.translation.y = position.y;
+        }
+    }
+
+    let mut bullets: HashMap<u32, Vec2> = kill_cam
+        .world
+        .query_filtered::<(&Rollback, &Position), With<Bullet>>()
+        .iter(&kill_cam.world)
+        .map(|(rollback, position)| (rollback.id(), position.0))
+        .collect();
+    for (entity, ghost, mut transform) in ghost_query.iter_mut() {
+        match bullets.remove(&ghost.0) {
+            Some(position) => {
+                transform.translation.x = position.x;
+                transform.translation.y = position.y;
+            }
+            None => commands.entity(entity).despawn(),
+        }
+    }
+    for (id, position) in bullets {
+        let transform = Transform::from_translation(position.extend(0.));
+        commands
+            .spawn_bundle(bullet_sprite())
+            .insert_bundle((transform, GlobalTransform::from(transform)))
+            .insert(KillCamBullet(id));
+    }
+
+    if let Ok((mut transform, mut projection)) = camera_query.get_single_mut() {
+        if let Some(position) = kill_cam.focus.and_then(|handle| players.get(&handle)) {
+            transform.translation.x = position.x;
+            transform.translation.y = position.y;
+            projection.scale = kill_cam.camera.1 * ZOOM;
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Starts a whole local match from a single command: `cargo run -- launch`.
+//! Every instance gets its own free port and the matching `--local-port`/`--players` arguments.
+//! Their logs are collected, prefixed with the instance number and optionally written to files.
+
+use crate::config::NUM_PLAYERS;
+use clap::Parser;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+/// Name of the subcommand, has to be the first argument
+const SUBCOMMAND: &str = "launch";
+
+#[derive(Parser, Debug)]
+#[clap(about = "Launches a local match with one instance of the game per player")]
+struct LaunchArgs {
+    /// Additionally write the log of every instance to `instance_<n>.log` in this directory
+    #[clap(long)]
+    log_dir: Option<PathBuf>,
+    /// Passed on to every instance, e.g. to simulate network conditions
+    #[clap(last = true)]
+    game_args: Vec<String>,
+}
+
+/// Runs the launcher if the game was started with the `launch` subcommand.
+/// Returns `false` if the game should start normally instead.
+pub fn run_if_requested() -> bool {
+    let mut args = std::env::args();
+    let executable = args.next();
+    if args.next().as_deref() != Some(SUBCOMMAND) {
+        return false;
+    }
+    let launch_args = LaunchArgs::parse_from(executable.into_iter().chain(args));
+    if let Err(error) = launch(launch_args) {
+        eprintln!("Failed to launch local match: {}", error);
+        std::process::exit(1);
+    }
+    true
+}
+
+fn launch(args: LaunchArgs) -> io::Result<()> {
+    if let Some(log_dir) = &args.log_dir {
+        fs::create_dir_all(log_dir)?;
+    }
+
+    // one instance per player, as matches always have `NUM_PLAYERS` players
+    let ports = free_ports(NUM_PLAYERS)?;
+    let executable = std::env::current_exe()?;
+    let mut children = Vec::new();
+    let mut log_threads = Vec::new();
+    for (instance, port) in ports.iter().enumerate() {
+        let players = ports.iter().enumerate().map(|(player, player_port)| {
+            if player == instance {
+                "localhost".to_string()
+            } else {
+                format!("127.0.0.1:{}", player_port)
+            }
+        });
+        let mut command = Command::new(&executable);
+        command
+            .arg("--local-port")
+            .arg(port.to_string())
+            .arg("--auto-connect")
+            .arg("--players")
+            .args(players)
+            .args(&args.game_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        println!("[launcher] Starting instance {}: {:?}", instance, command);
+        let mut child = command.spawn()?;
+
+        let log_file = match &args.log_dir {
+            Some(log_dir) => Some(File::create(
+                log_dir.join(format!("instance_{}.log", instance)),
+            )?),
+            None => None,
+        };
+        log_threads.extend(collect_logs(instance, &mut child, log_file)?);
+        children.push(child);
+    }
+
+    for (instance, mut child) in children.into_iter().enumerate() {
+        let status = child.wait()?;
+        println!("[launcher] Instance {} exited with {}", instance, status);
+    }
+    for thread in log_threads {
+        let _ = thread.join();
+    }
+    Ok(())
+}
+
+/// Asks the OS for free ports. They are released again before the instances bind them,
+/// which is fine for local development.
+fn free_ports(count: usize) -> io::Result<Vec<u16>> {
+    let sockets = (0..count)
+        .map(|_| UdpSocket::bind(("127.0.0.1", 0)))
+        .collect::<io::Result<Vec<_>>>()?;
+    sockets
+        .iter()
+        .map(|socket| socket.local_addr().map(|addr| addr.port()))
+        .collect()
+}
+
+fn collect_logs(
+    instance: usize,
+    child: &mut Child,
+    log_file: Option<File>,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    let stdout = child
+        .stdout
+        .take()
+        .map(|stdout| Box::new(stdout) as Box<dyn Read + Send>);
+    let stderr = child
+        .stderr
+        .take()
+        .map(|stderr| Box::new(stderr) as Box<dyn Read + Send>);
+    let mut threads = Vec::new();
+    for output in [stdout, stderr].into_iter().flatten() {
+        let log_file = match &log_file {
+            Some(log_file) => Some(log_file.try_clone()?),
+            None => None,
+        };
+        threads.push(thread::spawn(move || {
+            forward_lines(instance, output, log_file)
+        }));
+    }
+    Ok(threads)
+}
+
+fn forward_lines(instance: usize, output: Box<dyn Read + Send>, mut log_file: Option<File>) {
+    for line in BufReader::new(output).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        println!("[instance {}] {}", instance, line);
+        if let Some(log_file) = &mut log_file {
+            let _ = writeln!(log_file, "{}", line);
+        }
+    }
+}
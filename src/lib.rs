@@ -1,23 +1,62 @@
 mod actions;
+mod animation;
 mod audio;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod benchmark;
+mod combat;
 mod config;
+mod dash;
 mod dev;
+mod effects;
+mod events;
+mod history;
+mod hud;
+mod kill_cam;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod launcher;
 mod loading;
 mod menu;
 mod networking;
+mod pause;
 mod player;
+mod position;
+mod profile;
+mod replay;
+mod rng;
+mod round;
+mod settings;
+mod storage;
+mod tuning;
+mod weapon;
 
 use crate::actions::ActionsPlugin;
+use crate::animation::AnimationPlugin;
 use crate::audio::InternalAudioPlugin;
+use crate::combat::CombatPlugin;
+use crate::dash::DashPlugin;
 use crate::dev::DevPlugin;
+use crate::effects::EffectsPlugin;
+use crate::history::HistoryPlugin;
+use crate::hud::HudPlugin;
+use crate::kill_cam::KillCamPlugin;
 use crate::loading::LoadingPlugin;
 use crate::menu::MenuPlugin;
 use crate::networking::NetworkingPlugin;
+use crate::pause::PausePlugin;
 use crate::player::PlayerPlugin;
+use crate::position::PositionPlugin;
+use crate::profile::ProfilePlugin;
+use crate::replay::viewer::ReplayViewerPlugin;
+use crate::round::RoundPlugin;
+use crate::settings::SettingsPlugin;
+use crate::tuning::TuningPlugin;
+use crate::weapon::WeaponPlugin;
 
 use bevy::app::App;
 use bevy::prelude::*;
 
+pub use crate::settings::Settings;
+
 // This example game uses States to separate logic
 // See https://bevy-cheatbook.github.io/programming/states.html
 // Or https://github.com/bevyengine/bevy/blob/main/examples/ecs/state.rs
@@ -25,6 +64,8 @@ use bevy::prelude::*;
 enum GameState {
     // During the loading State the LoadingPlugin will load our assets
     Loading,
+    // During this State we find our opponents and make sure our game versions are compatible
+    Connecting,
     // During this State the actual game logic is executed
     Playing,
     // Here the menu is drawn and waiting for player interaction
@@ -37,11 +78,26 @@ impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
         app.add_state(GameState::Loading)
             .add_plugin(LoadingPlugin)
+            .add_plugin(TuningPlugin)
             .add_plugin(NetworkingPlugin)
             .add_plugin(MenuPlugin)
+            .add_plugin(PausePlugin)
+            .add_plugin(SettingsPlugin)
+            .add_plugin(ProfilePlugin)
             .add_plugin(ActionsPlugin)
             .add_plugin(InternalAudioPlugin)
             .add_plugin(PlayerPlugin)
+            .add_plugin(PositionPlugin)
+            .add_plugin(AnimationPlugin)
+            .add_plugin(CombatPlugin)
+            .add_plugin(WeaponPlugin)
+            .add_plugin(DashPlugin)
+            .add_plugin(RoundPlugin)
+            .add_plugin(EffectsPlugin)
+            .add_plugin(HudPlugin)
+            .add_plugin(HistoryPlugin)
+            .add_plugin(ReplayViewerPlugin)
+            .add_plugin(KillCamPlugin)
             .add_plugin(DevPlugin);
     }
 }
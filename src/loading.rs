@@ -1,22 +1,293 @@
+use crate::menu::ButtonColors;
+use crate::tuning::Tuning;
 use crate::GameState;
-use bevy::prelude::*;
-use bevy_asset_loader::{AssetCollection, AssetLoader};
+use bevy::asset::LoadState;
+use bevy::{log, prelude::*};
+use bevy_asset_loader::AssetCollection;
 use bevy_kira_audio::AudioSource;
 
 pub struct LoadingPlugin;
 
-/// This plugin loads all assets using [AssetLoader] from a third party bevy plugin
-/// Alternatively you can write the logic to load assets yourself
-/// If interested, take a look at https://bevy-cheatbook.github.io/features/assets.html
+/// Same font as in [`FontAssets`], used by the loading screen before the collection is ready
+const LOADING_SCREEN_FONT: &str = "fonts/FiraSans-Bold.ttf";
+
+/// This plugin loads all asset collections defined with `bevy_asset_loader` and shows the progress of each of them.
+/// Assets that fail to load are listed with their path and can be loaded again,
+/// so a missing file does not leave the game stuck in `GameState::Loading`.
+/// When everything is loaded, the collections are inserted as resources and the menu is opened.
 impl Plugin for LoadingPlugin {
     fn build(&self, app: &mut App) {
-        AssetLoader::new(GameState::Loading)
-            .with_collection::<FontAssets>()
-            .with_collection::<AudioAssets>()
-            .with_collection::<TextureAssets>()
-            .init_resource::<SpriteAssets>()
-            .continue_to_state(GameState::Menu)
-            .build(app);
+        app.insert_resource(
+            LoadingProgress::default()
+                .with_collection::<FontAssets>("Fonts")
+                .with_collection::<AudioAssets>("Audio")
+                .with_collection::<TextureAssets>("Textures")
+                .with_collection::<TuningAssets>("Tuning"),
+        )
+        .add_system_set(
+            SystemSet::on_enter(GameState::Loading)
+                .with_system(start_loading.exclusive_system())
+                .with_system(spawn_loading_screen),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameState::Loading)
+                .with_system(finish_loading.exclusive_system())
+                .with_system(update_loading_screen)
+                .with_system(show_retry_button)
+                .with_system(click_retry_button),
+        )
+        .add_system_set(SystemSet::on_exit(GameState::Loading).with_system(despawn_loading_screen));
+    }
+}
+
+struct LoadingCollection {
+    name: &'static str,
+    handles: Vec<HandleUntyped>,
+    load: fn(&mut World) -> Vec<HandleUntyped>,
+    /// Creates the collection from the loaded handles and inserts it as a resource
+    insert: fn(&mut World),
+}
+
+impl LoadingCollection {
+    fn loaded(&self, asset_server: &AssetServer) -> usize {
+        self.handles
+            .iter()
+            .filter(|handle| asset_server.get_load_state(*handle) == LoadState::Loaded)
+            .count()
+    }
+
+    /// Paths of the assets that failed to load
+    fn failed(&self, asset_server: &AssetServer) -> Vec<String> {
+        self.handles
+            .iter()
+            .filter(|handle| asset_server.get_load_state(*handle) == LoadState::Failed)
+            .map(|handle| match asset_server.get_handle_path(handle) {
+                Some(path) => path.path().display().to_string(),
+                None => format!("{:?}", handle.id),
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct LoadingProgress {
+    collections: Vec<LoadingCollection>,
+}
+
+impl LoadingProgress {
+    fn with_collection<C: AssetCollection>(mut self, name: &'static str) -> Self {
+        self.collections.push(LoadingCollection {
+            name,
+            handles: Vec::new(),
+            load: C::load,
+            insert: |world| {
+                let collection = C::create(world);
+                world.insert_resource(collection);
+            },
+        });
+        self
+    }
+
+    fn is_done(&self, asset_server: &AssetServer) -> bool {
+        self.collections
+            .iter()
+            .all(|collection| collection.loaded(asset_server) == collection.handles.len())
+    }
+}
+
+#[derive(Component)]
+struct LoadingScreen;
+
+#[derive(Component)]
+struct ProgressText;
+
+#[derive(Component)]
+struct RetryButton;
+
+fn start_loading(world: &mut World) {
+    world.resource_scope(|world, mut progress: Mut<LoadingProgress>| {
+        for collection in progress.collections.iter_mut() {
+            collection.handles = (collection.load)(world);
+        }
+    });
+}
+
+fn finish_loading(world: &mut World) {
+    let asset_server = world.get_resource::<AssetServer>().unwrap();
+    let progress = world.get_resource::<LoadingProgress>().unwrap();
+    if !progress.is_done(asset_server) {
+        return;
+    }
+    let inserts: Vec<_> = progress
+        .collections
+        .iter()
+        .map(|collection| collection.insert)
+        .collect();
+    for insert in inserts {
+        insert(world);
+    }
+    world.init_resource::<SpriteAssets>();
+    world
+        .get_resource_mut::<State<GameState>>()
+        .unwrap()
+        .set(GameState::Menu)
+        .unwrap();
+}
+
+fn spawn_loading_screen(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    button_colors: Res<ButtonColors>,
+) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .insert(LoadingScreen)
+        .insert(Name::new("Loading Screen"))
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle {
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font: asset_server.load(LOADING_SCREEN_FONT),
+                            font_size: 30.0,
+                            color: Color::rgb(0.9, 0.9, 0.9),
+                        },
+                        TextAlignment {
+                            horizontal: HorizontalAlign::Center,
+                            ..Default::default()
+                        },
+                    ),
+                    ..Default::default()
+                })
+                .insert(ProgressText);
+            // hidden until an asset fails to load
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(160.0), Val::Px(50.0)),
+                        margin: Rect::all(Val::Px(10.0)),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        display: Display::None,
+                        ..Default::default()
+                    },
+                    color: button_colors.normal,
+                    ..Default::default()
+                })
+                .insert(RetryButton)
+                .insert(Name::new("Retry Button"))
+                .with_children(|parent| {
+                    parent.spawn_bundle(TextBundle {
+                        text: Text::with_section(
+                            "Retry",
+                            TextStyle {
+                                font: asset_server.load(LOADING_SCREEN_FONT),
+                                font_size: 40.0,
+                                color: Color::rgb(0.9, 0.9, 0.9),
+                            },
+                            Default::default(),
+                        ),
+                        ..Default::default()
+                    });
+                });
+        });
+}
+
+fn update_loading_screen(
+    asset_server: Res<AssetServer>,
+    progress: Res<LoadingProgress>,
+    mut text_query: Query<&mut Text, With<ProgressText>>,
+) {
+    let mut lines = Vec::new();
+    let mut failures = Vec::new();
+    for collection in progress.collections.iter() {
+        lines.push(format!(
+            "{}: {}/{}",
+            collection.name,
+            collection.loaded(&asset_server),
+            collection.handles.len()
+        ));
+        failures.extend(collection.failed(&asset_server));
+    }
+    for failure in failures {
+        lines.push(format!("Failed to load {}", failure));
+    }
+    let value = lines.join("\n");
+    for mut text in text_query.iter_mut() {
+        if text.sections[0].value != value {
+            text.sections[0].value = value.clone();
+        }
+    }
+}
+
+fn failed_assets(asset_server: &AssetServer, progress: &LoadingProgress) -> Vec<String> {
+    progress
+        .collections
+        .iter()
+        .flat_map(|collection| collection.failed(asset_server))
+        .collect()
+}
+
+/// Shows the retry button while assets failed to load
+fn show_retry_button(
+    asset_server: Res<AssetServer>,
+    progress: Res<LoadingProgress>,
+    mut button_query: Query<&mut Style, With<RetryButton>>,
+) {
+    let display = if failed_assets(&asset_server, &progress).is_empty() {
+        Display::None
+    } else {
+        Display::Flex
+    };
+    for mut style in button_query.iter_mut() {
+        if style.display != display {
+            style.display = display;
+        }
+    }
+}
+
+/// Loads the failed assets again
+#[allow(clippy::type_complexity)]
+fn click_retry_button(
+    asset_server: Res<AssetServer>,
+    progress: Res<LoadingProgress>,
+    button_colors: Res<ButtonColors>,
+    mut button_query: Query<
+        (&Interaction, &mut UiColor),
+        (Changed<Interaction>, With<RetryButton>),
+    >,
+) {
+    for (interaction, mut color) in button_query.iter_mut() {
+        match *interaction {
+            Interaction::Clicked => {
+                for path in failed_assets(&asset_server, &progress) {
+                    log::info!("Retrying to load {}", path);
+                    asset_server.reload_asset(path.as_str());
+                }
+            }
+            Interaction::Hovered => *color = button_colors.hovered,
+            Interaction::None => *color = button_colors.normal,
+        }
+    }
+}
+
+fn despawn_loading_screen(
+    mut commands: Commands,
+    screen_query: Query<Entity, With<LoadingScreen>>,
+) {
+    for screen in screen_query.iter() {
+        commands.entity(screen).despawn_recursive();
     }
 }
 
@@ -33,31 +304,41 @@ pub struct FontAssets {
 pub struct AudioAssets {
     #[asset(path = "audio/flying.ogg")]
     pub flying: Handle<AudioSource>,
+    #[asset(path = "audio/music.wav")]
+    pub music: Handle<AudioSource>,
+    #[asset(path = "audio/fire.wav")]
+    pub fire: Handle<AudioSource>,
+    #[asset(path = "audio/hit.wav")]
+    pub hit: Handle<AudioSource>,
+    #[asset(path = "audio/death.wav")]
+    pub death: Handle<AudioSource>,
 }
 
 #[derive(AssetCollection)]
 pub struct TextureAssets {
-    #[asset(path = "textures/bevy.png")]
-    pub texture_bevy: Handle<Image>,
+    /// One row per [`crate::animation::Animation`]
+    #[asset(texture_atlas(tile_size_x = 32., tile_size_y = 32., columns = 4, rows = 5))]
+    #[asset(path = "textures/player.png")]
+    pub player_sheet: Handle<TextureAtlas>,
 }
 
+#[derive(AssetCollection)]
+pub struct TuningAssets {
+    #[asset(path = "tuning/gameplay.tuning.ron")]
+    pub gameplay: Handle<Tuning>,
+}
+
+/// Players are tinted in the colour of their profile, or the one from the [`Tuning`]
 #[derive(Debug, Clone)]
 pub struct SpriteAssets {
-    pub bevy_one: Sprite,
-    pub bevy_two: Sprite,
+    pub player: TextureAtlasSprite,
 }
 
 impl Default for SpriteAssets {
     fn default() -> Self {
         SpriteAssets {
-            bevy_one: Sprite {
-                custom_size: Some(Vec2::new(1.0, 1.0)),
-                color: Color::rgb(0.0, 0.8, 0.0),
-                ..default()
-            },
-            bevy_two: Sprite {
+            player: TextureAtlasSprite {
                 custom_size: Some(Vec2::new(1.0, 1.0)),
-                color: Color::rgb(0.8, 0.0, 0.0),
                 ..default()
             },
         }
@@ -1,21 +1,34 @@
 // disable console on windows for release builds
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+#[cfg(feature = "hot-reload")]
+use bevy::asset::AssetServerSettings;
 use bevy::prelude::{App, ClearColor, Color, Msaa, WindowDescriptor};
 use bevy::DefaultPlugins;
-use bevy_game::GamePlugin;
+use bevy_game::{GamePlugin, Settings};
 
 fn main() {
+    #[cfg(not(target_arch = "wasm32"))]
+    if bevy_game::launcher::run_if_requested() || bevy_game::benchmark::run_if_requested() {
+        return;
+    }
+
+    // the settings are needed before the window is created
+    let settings = Settings::load();
     let mut app = App::new();
-    app.insert_resource(Msaa { samples: 4 })
-        .insert_resource(ClearColor(Color::rgb(0.4, 0.4, 0.4)))
-        .insert_resource(WindowDescriptor {
-            width: 800.,
-            height: 600.,
-            title: "Bevy game".to_string(), // ToDo
-            ..Default::default()
-        })
-        .add_plugins(DefaultPlugins)
-        .add_plugin(GamePlugin)
-        .run();
+    app.insert_resource(Msaa {
+        samples: settings.msaa_samples,
+    })
+    .insert_resource(ClearColor(Color::rgb(0.4, 0.4, 0.4)))
+    .insert_resource(WindowDescriptor {
+        title: "Bevy game".to_string(), // ToDo
+        ..settings.window_descriptor()
+    })
+    .insert_resource(settings);
+    #[cfg(feature = "hot-reload")]
+    app.insert_resource(AssetServerSettings {
+        watch_for_changes: true,
+        ..Default::default()
+    });
+    app.add_plugins(DefaultPlugins).add_plugin(GamePlugin).run();
 }
@@ -1,22 +1,56 @@
+use crate::history::{open_history_menu, MatchHistory};
 use crate::loading::FontAssets;
+use crate::profile::{spawn_profile_editor, Profile};
+use crate::settings::{open_settings_menu, Settings};
 use crate::GameState;
 use bevy::prelude::*;
 
 pub struct MenuPlugin;
 
-/// This plugin is responsible for the game menu (containing only one button...)
-/// The menu is only drawn during the State `GameState::Menu` and is removed when that state is exited
+/// This plugin is responsible for the game menu (containing only a few buttons...)
+/// The menu is only drawn during the State `GameState::Menu` and is removed when that state is exited.
+/// While we connect to our peers, a screen with a button to cancel is shown instead.
 impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<ButtonColors>()
+            .add_startup_system(spawn_ui_camera)
             .add_system_set(SystemSet::on_enter(GameState::Menu).with_system(setup_menu))
-            .add_system_set(SystemSet::on_update(GameState::Menu).with_system(click_play_button));
+            .add_system_set(SystemSet::on_update(GameState::Menu).with_system(click_menu_buttons))
+            .add_system_set(SystemSet::on_exit(GameState::Menu).with_system(cleanup_menu))
+            .add_system_set(
+                SystemSet::on_enter(GameState::Connecting).with_system(setup_connecting_screen),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Connecting).with_system(click_cancel_button),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Connecting).with_system(cleanup_connecting_screen),
+            );
     }
 }
 
-struct ButtonColors {
-    normal: UiColor,
-    hovered: UiColor,
+/// Message shown in the menu, e.g. to tell the player why they were sent back from a match
+pub struct MenuMessage(pub String);
+
+#[derive(Component)]
+struct MenuRoot;
+
+#[derive(Component)]
+struct ConnectingScreen;
+
+#[derive(Component)]
+struct CancelButton;
+
+#[derive(Component, Clone, Copy)]
+enum MenuButton {
+    Play,
+    Settings,
+    History,
+}
+
+pub struct ButtonColors {
+    pub normal: UiColor,
+    pub hovered: UiColor,
 }
 
 impl Default for ButtonColors {
@@ -28,62 +62,201 @@ impl Default for ButtonColors {
     }
 }
 
+fn spawn_ui_camera(mut commands: Commands) {
+    commands
+        .spawn_bundle(UiCameraBundle::default())
+        .insert(Name::new("UI Camera"));
+}
+
 fn setup_menu(
     mut commands: Commands,
     font_assets: Res<FontAssets>,
     button_colors: Res<ButtonColors>,
+    profile: Res<Profile>,
+    message: Option<Res<MenuMessage>>,
 ) {
     commands
-        .spawn_bundle(UiCameraBundle::default())
-        .insert(Name::new("UI Camera"));
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .insert(MenuRoot)
+        .insert(Name::new("Menu"))
+        .with_children(|parent| {
+            spawn_profile_editor(parent, &font_assets, &button_colors, &profile);
+            for (button, label) in [
+                (MenuButton::Play, "Play"),
+                (MenuButton::Settings, "Settings"),
+                (MenuButton::History, "History"),
+            ] {
+                parent
+                    .spawn_bundle(ButtonBundle {
+                        style: Style {
+                            size: Size::new(Val::Px(160.0), Val::Px(50.0)),
+                            margin: Rect::all(Val::Px(10.0)),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        color: button_colors.normal,
+                        ..Default::default()
+                    })
+                    .insert(button)
+                    .insert(Name::new(format!("{} Button", label)))
+                    .with_children(|parent| {
+                        parent
+                            .spawn_bundle(TextBundle {
+                                text: Text {
+                                    sections: vec![TextSection {
+                                        value: label.to_string(),
+                                        style: TextStyle {
+                                            font: font_assets.fira_sans.clone(),
+                                            font_size: 40.0,
+                                            color: Color::rgb(0.9, 0.9, 0.9),
+                                        },
+                                    }],
+                                    alignment: Default::default(),
+                                },
+                                ..Default::default()
+                            })
+                            .insert(Name::new(format!("{} Text", label)));
+                    });
+            }
+
+            if let Some(message) = message {
+                parent
+                    .spawn_bundle(TextBundle {
+                        text: Text::with_section(
+                            message.0.clone(),
+                            TextStyle {
+                                font: font_assets.fira_sans.clone(),
+                                font_size: 20.0,
+                                color: Color::rgb(0.9, 0.3, 0.3),
+                            },
+                            Default::default(),
+                        ),
+                        ..Default::default()
+                    })
+                    .insert(Name::new("Menu Message"));
+            }
+        });
+}
+
+#[allow(clippy::type_complexity)]
+fn click_menu_buttons(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    button_colors: Res<ButtonColors>,
+    settings: Res<Settings>,
+    history: Res<MatchHistory>,
+    mut state: ResMut<State<GameState>>,
+    mut interaction_query: Query<(&MenuButton, &Interaction, &mut UiColor), Changed<Interaction>>,
+) {
+    for (button, interaction, mut color) in interaction_query.iter_mut() {
+        match *interaction {
+            Interaction::Clicked => match button {
+                MenuButton::Play => state.set(GameState::Connecting).unwrap(),
+                MenuButton::Settings => {
+                    open_settings_menu(&mut commands, &font_assets, &button_colors, &settings)
+                }
+                MenuButton::History => {
+                    open_history_menu(&mut commands, &font_assets, &button_colors, &history)
+                }
+            },
+            Interaction::Hovered => {
+                *color = button_colors.hovered;
+            }
+            Interaction::None => {
+                *color = button_colors.normal;
+            }
+        }
+    }
+}
+
+fn cleanup_menu(mut commands: Commands, menu_query: Query<Entity, With<MenuRoot>>) {
+    for menu in menu_query.iter() {
+        commands.entity(menu).despawn_recursive();
+    }
+    // the message has been shown, don't show it again next time
+    commands.remove_resource::<MenuMessage>();
+}
+
+fn setup_connecting_screen(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    button_colors: Res<ButtonColors>,
+) {
+    let text_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 40.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
     commands
-        .spawn_bundle(ButtonBundle {
+        .spawn_bundle(NodeBundle {
             style: Style {
-                size: Size::new(Val::Px(120.0), Val::Px(50.0)),
-                margin: Rect::all(Val::Auto),
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                flex_direction: FlexDirection::ColumnReverse,
                 justify_content: JustifyContent::Center,
                 align_items: AlignItems::Center,
                 ..Default::default()
             },
-            color: button_colors.normal,
+            color: Color::NONE.into(),
             ..Default::default()
         })
-        .insert(Name::new("Play Button"))
+        .insert(ConnectingScreen)
+        .insert(Name::new("Connecting Screen"))
         .with_children(|parent| {
             parent
                 .spawn_bundle(TextBundle {
-                    text: Text {
-                        sections: vec![TextSection {
-                            value: "Play".to_string(),
-                            style: TextStyle {
-                                font: font_assets.fira_sans.clone(),
-                                font_size: 40.0,
-                                color: Color::rgb(0.9, 0.9, 0.9),
-                            },
-                        }],
-                        alignment: Default::default(),
+                    text: Text::with_section("Connecting…", text_style.clone(), default()),
+                    ..Default::default()
+                })
+                .insert(Name::new("Connecting Text"));
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(160.0), Val::Px(50.0)),
+                        margin: Rect::all(Val::Px(10.0)),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..Default::default()
                     },
+                    color: button_colors.normal,
                     ..Default::default()
                 })
-                .insert(Name::new("Play Text"));
+                .insert(CancelButton)
+                .insert(Name::new("Cancel Button"))
+                .with_children(|parent| {
+                    parent.spawn_bundle(TextBundle {
+                        text: Text::with_section("Cancel", text_style, default()),
+                        ..Default::default()
+                    });
+                });
         });
 }
 
+/// Leaving `GameState::Connecting` drops the pending session, see [`crate::networking::NetworkingPlugin`]
 #[allow(clippy::type_complexity)]
-fn click_play_button(
-    mut commands: Commands,
+fn click_cancel_button(
     button_colors: Res<ButtonColors>,
     mut state: ResMut<State<GameState>>,
     mut interaction_query: Query<
-        (Entity, &Interaction, &mut UiColor),
-        (Changed<Interaction>, With<Button>),
+        (&Interaction, &mut UiColor),
+        (Changed<Interaction>, With<CancelButton>),
     >,
 ) {
-    for (button, interaction, mut color) in interaction_query.iter_mut() {
+    for (interaction, mut color) in interaction_query.iter_mut() {
         match *interaction {
             Interaction::Clicked => {
-                commands.entity(button).despawn_recursive();
-                state.set(GameState::Playing).unwrap();
+                // fails if the session started in the meantime, then the player can leave the match instead
+                let _ = state.set(GameState::Menu);
             }
             Interaction::Hovered => {
                 *color = button_colors.hovered;
@@ -94,3 +267,12 @@ fn click_play_button(
         }
     }
 }
+
+fn cleanup_connecting_screen(
+    mut commands: Commands,
+    screen_query: Query<Entity, With<ConnectingScreen>>,
+) {
+    for screen in screen_query.iter() {
+        commands.entity(screen).despawn_recursive();
+    }
+}
@@ -1,45 +1,329 @@
 use crate::actions::{create_input_protocol, set_movement_actions, Actions};
+use crate::animation::{update_animations, AnimationState};
+use crate::combat::{fire_bullets, hit_players, move_bullets, Bullet, FireCooldown, Health, Score};
 use crate::config::FPS;
+use crate::dash::{start_dashes, Dash};
+use crate::events::GameplayEvents;
+use crate::kill_cam::{buffer_inputs, buffer_snapshot, reset_kill_cam_buffer, KillCamBuffer};
+use crate::menu::MenuMessage;
+use crate::pause::run_simulation;
 use crate::player::move_players;
+use crate::position::{apply_corrections, record_predictions, Position};
+use crate::round::{advance_round, Round};
+use crate::weapon::{pick_up_weapons, Weapon, WeaponPickup};
 use crate::GameState;
-use bevy::prelude::*;
-use bevy_ggrs::GGRSPlugin;
+use bevy::{
+    log,
+    prelude::*,
+    reflect::{GetTypeRegistration, TypeRegistry},
+};
+use bevy_ggrs::{GGRSPlugin, SessionType};
+use ggrs::{Config, P2PSession, PlayerHandle, SpectatorSession};
+pub mod budget;
+pub mod desync;
+pub mod error;
+mod handshake;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod headless;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod loopback;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod simulator;
+mod socket;
+pub mod sync;
 mod targets;
-use targets::{PlatformConfig, PlatformPlugin};
+#[cfg(test)]
+mod tests;
+use crate::replay::{record_inputs, reset_replay_recorder, ReplayRecorder};
+use crate::rng::{reset_rng, GameRng, MatchSeed};
+use budget::{
+    report_rollback_budget, setup_budget_diagnostics, start_frame_timer, start_snapshot_timer,
+    stop_frame_timer, stop_snapshot_timer, RollbackTimings,
+};
+use desync::{
+    exchange_checksums, record_checksum, reset_desync_detector, ChecksumMailbox, DesyncDetector,
+};
+use error::NetworkingError;
+use protocol::{InputProtocol, LocalHandles};
+use sync::{reset_sync_status, update_sync_status, FrameSkipStage, SyncStatus};
+pub(crate) use targets::PlatformConfig;
+use targets::{PendingSession, PlatformPlugin};
 pub mod protocol;
 
 pub struct NetworkingPlugin;
 const ROLLBACK_SYSTEMS: &str = "rollback_systems";
+const SNAPSHOT_TIMER_START: &str = "snapshot_timer_start";
+const SNAPSHOT_TIMER_STOP: &str = "snapshot_timer_stop";
+const FRAME_SKIP: &str = "frame_skip";
 
 #[derive(SystemLabel, Debug, Clone, Hash, Eq, PartialEq)]
 enum Systems {
+    StartTimer,
+    Frame,
+    Corrections,
     Input,
+    Dash,
     Move,
+    Pickup,
+    Fire,
+    MoveBullets,
+    Hit,
+    Round,
+    Predictions,
+    Animations,
+}
+
+/// Number of the frame that is currently simulated.
+/// As it is rolled back as well, it is also correct while resimulating frames.
+#[derive(Reflect, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect(Resource)]
+pub struct FrameCount {
+    pub frame: u32,
+}
+
+/// Whether the frame that is currently simulated has been simulated before. Not rolled back.
+#[derive(Default, Debug)]
+pub struct RollbackStatus {
+    /// Latest frame that was simulated so far
+    pub latest_frame: u32,
+    pub resimulating: bool,
 }
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
-        GGRSPlugin::<PlatformConfig>::new()
-            .with_input_system(create_input_protocol)
-            .with_update_frequency(FPS)
-            .with_rollback_schedule(
-                Schedule::default().with_stage(
-                    ROLLBACK_SYSTEMS,
-                    SystemStage::parallel()
-                        .with_system_set(State::<GameState>::get_driver())
-                        .with_system_set(
-                            SystemSet::on_update(GameState::Playing)
-                                .with_system(set_movement_actions.label(Systems::Input))
-                                .with_system(
-                                    move_players.label(Systems::Move).after(Systems::Input),
-                                ),
-                        ),
-                ),
+        add_rollback_simulation::<PlatformConfig, _>(app, create_input_protocol);
+
+        app.add_startup_system(setup_budget_diagnostics)
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(report_rollback_budget)
+                    .with_system(update_sync_status),
+            )
+            .add_system(handle_networking_errors)
+            .add_system_set(
+                SystemSet::on_exit(GameState::Connecting).with_system(abort_pending_session),
+            )
+            .add_system_set(
+                SystemSet::on_enter(GameState::Playing)
+                    .with_system(reset_frame)
+                    .with_system(reset_rng)
+                    .with_system(reset_sync_status)
+                    .with_system(reset_desync_detector)
+                    .with_system(reset_replay_recorder)
+                    .with_system(reset_kill_cam_buffer),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(end_session))
+            .add_plugin(PlatformPlugin::default());
+    }
+}
+
+/// Adds the GGRS plugin with our rollback schedule and the resources the schedule needs.
+/// Sessions using a different [`Config`], e.g. in tests, can be driven by their own `input_system`.
+pub(crate) fn add_rollback_simulation<T, Params>(
+    app: &mut App,
+    input_system: impl IntoSystem<PlayerHandle, InputProtocol, Params>,
+) where
+    T: Config<Input = InputProtocol>,
+{
+    // the GGRS plugin adds its stage to a schedule of its own, which is run by our `FrameSkipStage`
+    let schedule = std::mem::replace(
+        &mut app.schedule,
+        Schedule::default()
+            .with_stage(CoreStage::First, SystemStage::parallel())
+            .with_stage(CoreStage::PreUpdate, SystemStage::parallel())
+            .with_stage(CoreStage::Update, SystemStage::parallel())
+            .with_stage(CoreStage::PostUpdate, SystemStage::parallel())
+            .with_stage(CoreStage::Last, SystemStage::parallel()),
+    );
+    GGRSPlugin::<T>::new()
+        .with_input_system(input_system)
+        .with_update_frequency(FPS)
+        .with_rollback_schedule(rollback_schedule())
+        .register_rollback_types()
+        .build(app);
+    let ggrs_schedule = std::mem::replace(&mut app.schedule, schedule);
+    app.add_stage_after(
+        CoreStage::PreUpdate,
+        FRAME_SKIP,
+        FrameSkipStage::<T>::new(ggrs_schedule),
+    );
+
+    // the GGRS stage runs between `PreUpdate` and `Update`, these stages enclose it
+    app.add_stage_after(
+        CoreStage::PreUpdate,
+        SNAPSHOT_TIMER_START,
+        SystemStage::single(start_snapshot_timer),
+    )
+    .add_stage_before(
+        CoreStage::Update,
+        SNAPSHOT_TIMER_STOP,
+        SystemStage::single(stop_snapshot_timer),
+    );
+
+    app.add_event::<NetworkingError>()
+        .init_resource::<FrameCount>()
+        .init_resource::<MatchSeed>()
+        .init_resource::<GameRng>()
+        .init_resource::<RollbackStatus>()
+        .init_resource::<RollbackTimings>()
+        .init_resource::<SyncStatus>()
+        .init_resource::<DesyncDetector>()
+        .init_resource::<ReplayRecorder>()
+        .init_resource::<KillCamBuffer>()
+        .init_resource::<GameplayEvents>()
+        .add_system_set(SystemSet::on_update(GameState::Playing).with_system(exchange_checksums));
+}
+
+/// The gameplay systems, run by GGRS for every simulated frame.
+/// Replays run their own copy of it, see [`crate::replay`].
+pub(crate) fn rollback_schedule() -> Schedule {
+    Schedule::default().with_stage(
+        ROLLBACK_SYSTEMS,
+        SystemStage::parallel()
+            .with_system_set(State::<GameState>::get_driver())
+            .with_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_simulation)
+                    .with_system(start_frame_timer.label(Systems::StartTimer))
+                    .with_system(
+                        advance_frame
+                            .label(Systems::Frame)
+                            .after(Systems::StartTimer),
+                    )
+                    .with_system(
+                        apply_corrections
+                            .label(Systems::Corrections)
+                            .after(Systems::Frame),
+                    )
+                    .with_system(
+                        set_movement_actions
+                            .label(Systems::Input)
+                            .after(Systems::Corrections),
+                    )
+                    .with_system(record_inputs.after(Systems::Input))
+                    .with_system(buffer_inputs.after(Systems::Input))
+                    .with_system(start_dashes.label(Systems::Dash).after(Systems::Input))
+                    .with_system(move_players.label(Systems::Move).after(Systems::Dash))
+                    .with_system(pick_up_weapons.label(Systems::Pickup).after(Systems::Move))
+                    .with_system(fire_bullets.label(Systems::Fire).after(Systems::Pickup))
+                    .with_system(
+                        move_bullets
+                            .label(Systems::MoveBullets)
+                            .after(Systems::Fire),
+                    )
+                    .with_system(hit_players.label(Systems::Hit).after(Systems::MoveBullets))
+                    .with_system(advance_round.label(Systems::Round).after(Systems::Hit))
+                    .with_system(
+                        record_predictions
+                            .label(Systems::Predictions)
+                            .after(Systems::Round),
+                    )
+                    .with_system(
+                        update_animations
+                            .label(Systems::Animations)
+                            .after(Systems::Round),
+                    )
+                    .with_system(
+                        record_checksum
+                            .after(Systems::Predictions)
+                            .after(Systems::Animations),
+                    )
+                    .with_system(
+                        stop_frame_timer
+                            .after(Systems::Predictions)
+                            .after(Systems::Animations),
+                    ),
             )
-            .register_rollback_type::<Transform>()
-            .register_rollback_type::<Actions>()
-            .build(app);
+            .with_system(buffer_snapshot.exclusive_system().at_end()),
+    )
+}
+
+/// Something the types that are saved and restored on rollbacks can be registered with
+pub(crate) trait RegisterRollbackTypes: Sized {
+    fn with_rollback_type<Type: GetTypeRegistration>(self) -> Self;
+
+    fn register_rollback_types(self) -> Self {
+        self.with_rollback_type::<Position>()
+            .with_rollback_type::<Actions>()
+            .with_rollback_type::<FrameCount>()
+            .with_rollback_type::<Health>()
+            .with_rollback_type::<FireCooldown>()
+            .with_rollback_type::<Weapon>()
+            .with_rollback_type::<WeaponPickup>()
+            .with_rollback_type::<Dash>()
+            .with_rollback_type::<Score>()
+            .with_rollback_type::<Round>()
+            .with_rollback_type::<GameRng>()
+            .with_rollback_type::<AnimationState>()
+            .with_rollback_type::<Bullet>()
+    }
+}
+
+impl<T: Config> RegisterRollbackTypes for GGRSPlugin<T> {
+    fn with_rollback_type<Type: GetTypeRegistration>(self) -> Self {
+        self.register_rollback_type::<Type>()
+    }
+}
+
+impl RegisterRollbackTypes for TypeRegistry {
+    fn with_rollback_type<Type: GetTypeRegistration>(mut self) -> Self {
+        self.register::<Type>();
+        self
+    }
+}
+
+fn advance_frame(
+    mut frame_count: ResMut<FrameCount>,
+    mut status: ResMut<RollbackStatus>,
+    mut events: ResMut<GameplayEvents>,
+) {
+    frame_count.frame += 1;
+    status.resimulating = frame_count.frame <= status.latest_frame;
+    status.latest_frame = status.latest_frame.max(frame_count.frame);
+    events.start_frame(frame_count.frame);
+}
+
+fn reset_frame(
+    mut frame_count: ResMut<FrameCount>,
+    mut status: ResMut<RollbackStatus>,
+    mut events: ResMut<GameplayEvents>,
+) {
+    *frame_count = default();
+    *status = default();
+    *events = default();
+}
+
+/// Ends the current session and goes back to the menu, where the error is shown
+fn handle_networking_errors(
+    mut commands: Commands,
+    mut errors: EventReader<NetworkingError>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let mut message = None;
+    for error in errors.iter() {
+        log::error!("Networking error: {}", error);
+        // later errors are most likely a consequence of the first one
+        message.get_or_insert_with(|| error.to_string());
+    }
 
-        app.add_plugin(PlatformPlugin::default());
+    let message = match message {
+        Some(message) => message,
+        None => return,
+    };
+    if matches!(state.current(), GameState::Connecting | GameState::Playing) {
+        commands.insert_resource(MenuMessage(message));
+        state.overwrite_set(GameState::Menu).unwrap();
     }
 }
+
+fn abort_pending_session(mut pending_session: ResMut<Option<PendingSession<PlatformConfig>>>) {
+    *pending_session = None;
+}
+
+fn end_session(mut commands: Commands) {
+    commands.remove_resource::<P2PSession<PlatformConfig>>();
+    commands.remove_resource::<SpectatorSession<PlatformConfig>>();
+    commands.remove_resource::<SessionType>();
+    commands.remove_resource::<LocalHandles>();
+    commands.remove_resource::<ChecksumMailbox>();
+}
@@ -0,0 +1,190 @@
+use crate::config::{FPS, MAX_PREDICTION_WINDOW};
+use crate::networking::RollbackStatus;
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics},
+    log,
+    prelude::*,
+    utils::{Duration, Instant},
+};
+use std::collections::VecDeque;
+
+/// Average time it took to simulate one rollback frame, in milliseconds
+pub const ROLLBACK_FRAME_TIME: DiagnosticId =
+    DiagnosticId::from_u128(0x3d6f0c42_8a1b_4e57_9c2d_51f0a7b3e964);
+/// Number of frames that were simulated again because of a rollback, per update
+pub const RESIMULATED_FRAMES: DiagnosticId =
+    DiagnosticId::from_u128(0x9b27e5d1_40c3_4f8a_b6e2_0d7c3a915f28);
+/// Time the slowest rollback frame would take if the whole prediction window had to be resimulated, in milliseconds
+pub const WORST_CASE_ROLLBACK_TIME: DiagnosticId =
+    DiagnosticId::from_u128(0x61c8a0f4_2e9d_4b13_8f75_c4a29d06e7b1);
+
+/// Number of frames whose timings are kept to estimate the worst case
+const HISTORY_FRAMES: usize = 2 * FPS;
+/// Minimum time between two warnings about exceeding the budget
+const WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time available for all rollback frames of one update
+pub fn frame_budget() -> Duration {
+    Duration::from_secs_f64(1. / FPS as f64)
+}
+
+/// How long the rollback schedule took. Not rolled back.
+/// Besides our own systems, this covers the time GGRS spends between frames,
+/// which is mostly saving snapshots and loading one on a rollback.
+#[derive(Default, Debug)]
+pub struct RollbackTimings {
+    frame_started: Option<Instant>,
+    /// When GGRS last got control, i.e. the GGRS stage started or a frame ended
+    snapshot_started: Option<Instant>,
+    /// Durations of the latest simulated frames, including resimulated ones
+    recent_frames: VecDeque<Duration>,
+    /// Time GGRS spent before each of the latest frames
+    recent_snapshots: VecDeque<Duration>,
+    /// Frames simulated since the last update
+    frames: u32,
+    resimulated_frames: u32,
+    total: Duration,
+    totals: RollbackTotals,
+}
+
+/// Timings of every frame simulated since the timings were created
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RollbackTotals {
+    pub frames: u64,
+    pub resimulated_frames: u64,
+    pub time: Duration,
+    pub slowest_frame: Duration,
+    /// Time GGRS spent saving and loading snapshots between frames
+    pub snapshot_time: Duration,
+    pub slowest_snapshot: Duration,
+}
+
+impl RollbackTimings {
+    fn record(&mut self, duration: Duration, resimulated: bool) {
+        if self.recent_frames.len() == HISTORY_FRAMES {
+            self.recent_frames.pop_front();
+        }
+        self.recent_frames.push_back(duration);
+        self.frames += 1;
+        self.resimulated_frames += resimulated as u32;
+        self.total += duration;
+        self.totals.frames += 1;
+        self.totals.resimulated_frames += resimulated as u64;
+        self.totals.time += duration;
+        self.totals.slowest_frame = self.totals.slowest_frame.max(duration);
+    }
+
+    fn record_snapshot(&mut self, duration: Duration) {
+        if self.recent_snapshots.len() == HISTORY_FRAMES {
+            self.recent_snapshots.pop_front();
+        }
+        self.recent_snapshots.push_back(duration);
+        self.totals.snapshot_time += duration;
+        self.totals.slowest_snapshot = self.totals.slowest_snapshot.max(duration);
+    }
+
+    pub fn totals(&self) -> RollbackTotals {
+        self.totals
+    }
+
+    pub fn slowest_frame(&self) -> Duration {
+        self.recent_frames.iter().max().copied().unwrap_or_default()
+    }
+
+    pub fn slowest_snapshot(&self) -> Duration {
+        self.recent_snapshots
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Time one update would take if it had to roll back the whole prediction window
+    /// and then simulate the new frame, with every frame and snapshot as slow as the slowest recent one
+    pub fn worst_case(&self) -> Duration {
+        (self.slowest_frame() + self.slowest_snapshot()) * (MAX_PREDICTION_WINDOW as u32 + 1)
+    }
+
+    /// Average time of the frames simulated since the last call, and how many of them were resimulated
+    pub fn take_update(&mut self) -> Option<(Duration, u32)> {
+        let update = (self.frames > 0).then(|| (self.total / self.frames, self.resimulated_frames));
+        self.frames = 0;
+        self.resimulated_frames = 0;
+        self.total = Duration::ZERO;
+        update
+    }
+}
+
+/// Runs right before the GGRS stage
+pub fn start_snapshot_timer(mut timings: ResMut<RollbackTimings>) {
+    timings.snapshot_started = Some(Instant::now());
+}
+
+/// Runs right after the GGRS stage. The time after the last frame is spent on the next update's input, not snapshots.
+pub fn stop_snapshot_timer(mut timings: ResMut<RollbackTimings>) {
+    timings.snapshot_started = None;
+}
+
+/// Rollback system, runs before anything else in a frame
+pub fn start_frame_timer(mut timings: ResMut<RollbackTimings>) {
+    let now = Instant::now();
+    if let Some(started) = timings.snapshot_started.take() {
+        timings.record_snapshot(now - started);
+    }
+    timings.frame_started = Some(now);
+}
+
+/// Rollback system, runs after everything else in a frame
+pub fn stop_frame_timer(mut timings: ResMut<RollbackTimings>, status: Res<RollbackStatus>) {
+    let now = Instant::now();
+    if let Some(started) = timings.frame_started.take() {
+        timings.record(now - started, status.resimulating);
+    }
+    timings.snapshot_started = Some(now);
+}
+
+pub fn setup_budget_diagnostics(mut diagnostics: ResMut<Diagnostics>) {
+    diagnostics
+        .add(Diagnostic::new(ROLLBACK_FRAME_TIME, "rollback_frame_time", 20).with_suffix("ms"));
+    diagnostics.add(Diagnostic::new(
+        RESIMULATED_FRAMES,
+        "resimulated_frames",
+        20,
+    ));
+    diagnostics.add(
+        Diagnostic::new(WORST_CASE_ROLLBACK_TIME, "worst_case_rollback_time", 20).with_suffix("ms"),
+    );
+}
+
+/// Publishes the timings of the frames simulated during this update
+/// and warns when a full rollback would no longer fit into a single frame
+pub fn report_rollback_budget(
+    mut timings: ResMut<RollbackTimings>,
+    mut diagnostics: ResMut<Diagnostics>,
+    mut last_warning: Local<Option<Instant>>,
+) {
+    let (average, resimulated) = match timings.take_update() {
+        Some(update) => update,
+        None => return,
+    };
+    let worst_case = timings.worst_case();
+    diagnostics.add_measurement(ROLLBACK_FRAME_TIME, average.as_secs_f64() * 1000.);
+    diagnostics.add_measurement(RESIMULATED_FRAMES, resimulated as f64);
+    diagnostics.add_measurement(WORST_CASE_ROLLBACK_TIME, worst_case.as_secs_f64() * 1000.);
+
+    if worst_case <= frame_budget() {
+        return;
+    }
+    if last_warning.map_or(false, |warned| warned.elapsed() < WARNING_INTERVAL) {
+        return;
+    }
+    *last_warning = Some(Instant::now());
+    log::warn!(
+        "Rolling back {} frames would take {:?}, exceeding the budget of {:?} per frame (slowest frame took {:?}, slowest snapshot {:?})",
+        MAX_PREDICTION_WINDOW,
+        worst_case,
+        frame_budget(),
+        timings.slowest_frame(),
+        timings.slowest_snapshot()
+    );
+}
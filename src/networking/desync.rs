@@ -0,0 +1,213 @@
+use crate::combat::{Bullet, FireCooldown, Health, Score};
+use crate::config::MAX_PREDICTION_WINDOW;
+use crate::dash::Dash;
+use crate::networking::FrameCount;
+use crate::player::Player;
+use crate::position::Position;
+use crate::rng::GameRng;
+use crate::round::Round;
+use crate::weapon::{Weapon, WeaponPickup};
+use bevy::{log, prelude::*, utils::HashMap};
+use bevy_ggrs::Rollback;
+use ggrs::PlayerHandle;
+use std::sync::{Arc, Mutex};
+
+/// Checksums are exchanged for frames this old.
+/// A frame this old can't be rolled back anymore, so its checksum is the same on every peer unless they desynced.
+const CHECKSUM_AGE: u32 = MAX_PREDICTION_WINDOW as u32 + 1;
+/// Frames whose checksums are kept
+const CHECKSUM_HISTORY: u32 = 128;
+
+/// Detects peers whose simulation diverged from ours.
+/// Every peer sends checksums of its state at frames that can't be rolled back anymore through the [`ChecksumMailbox`],
+/// which every peer compares to its own checksum of that frame. Not rolled back.
+#[derive(Default, Debug)]
+pub struct DesyncDetector {
+    checksums: HashMap<u32, u64>,
+    /// Latest frame whose checksum was sent to our peers
+    sent_frame: u32,
+    /// Checksums our peers sent for frames we can still roll back, by the player who sent them
+    received: Vec<(PlayerHandle, u32, u64)>,
+    /// First frame at which a peer's state differed from ours
+    pub desync_frame: Option<u32>,
+}
+
+/// Checksums to and from our peers, shared with the [`crate::networking::socket::GameSocket`] of the running session.
+/// They are sent on a channel of their own, so that they don't change our inputs, which GGRS has to predict.
+#[derive(Default, Clone)]
+pub struct ChecksumMailbox(Arc<Mutex<Mailbox>>);
+
+#[derive(Default)]
+struct Mailbox {
+    /// Frames and checksums to send to every peer
+    outgoing: Vec<(u32, u64)>,
+    /// Frames and checksums received from our peers, with the player who sent them
+    incoming: Vec<(PlayerHandle, u32, u64)>,
+}
+
+impl ChecksumMailbox {
+    pub fn send(&self, frame: u32, checksum: u64) {
+        self.0.lock().unwrap().outgoing.push((frame, checksum));
+    }
+
+    /// Takes the checksums that have to be sent to every peer
+    pub fn take_outgoing(&self) -> Vec<(u32, u64)> {
+        std::mem::take(&mut self.0.lock().unwrap().outgoing)
+    }
+
+    pub fn deliver(&self, handle: PlayerHandle, frame: u32, checksum: u64) {
+        self.0
+            .lock()
+            .unwrap()
+            .incoming
+            .push((handle, frame, checksum));
+    }
+
+    /// Takes the checksums our peers sent us
+    pub fn take_incoming(&self) -> Vec<(PlayerHandle, u32, u64)> {
+        std::mem::take(&mut self.0.lock().unwrap().incoming)
+    }
+}
+
+pub fn reset_desync_detector(mut detector: ResMut<DesyncDetector>) {
+    *detector = default();
+}
+
+/// Sends the checksums of frames that became too old to be rolled back, and compares them to the ones our peers sent
+pub fn exchange_checksums(
+    frame_count: Res<FrameCount>,
+    mailbox: Option<Res<ChecksumMailbox>>,
+    mut detector: ResMut<DesyncDetector>,
+) {
+    let mailbox = match mailbox {
+        Some(mailbox) => mailbox,
+        // only P2P sessions have peers to compare with
+        None => return,
+    };
+    let confirmed_frame = match frame_count.frame.checked_sub(CHECKSUM_AGE) {
+        Some(frame) => frame,
+        None => return,
+    };
+
+    for frame in detector.sent_frame + 1..=confirmed_frame {
+        if let Some(checksum) = detector.checksums.get(&frame) {
+            mailbox.send(frame, *checksum);
+        }
+    }
+    detector.sent_frame = detector.sent_frame.max(confirmed_frame);
+
+    detector.received.extend(mailbox.take_incoming());
+    if detector.desync_frame.is_some() {
+        detector.received.clear();
+        return;
+    }
+    // our own checksums of later frames may still change
+    let (compared, pending) = detector
+        .received
+        .drain(..)
+        .partition::<Vec<_>, _>(|(_, frame, _)| *frame <= confirmed_frame);
+    detector.received = pending;
+    for (handle, frame, checksum) in compared {
+        // checksums we no longer have can't be compared, which only happens if the peer is far behind us
+        if detector
+            .checksums
+            .get(&frame)
+            .map_or(false, |own| *own != checksum)
+        {
+            log::error!(
+                "Desync detected: player {} disagrees with our state at frame {}",
+                handle,
+                frame
+            );
+            detector.desync_frame = Some(frame);
+            return;
+        }
+    }
+}
+
+/// Rollback system, runs after everything else in a frame
+pub fn record_checksum(
+    frame_count: Res<FrameCount>,
+    round: Res<Round>,
+    rng: Res<GameRng>,
+    mut detector: ResMut<DesyncDetector>,
+    player_query: Query<(
+        &Player,
+        &Position,
+        &Health,
+        &FireCooldown,
+        &Weapon,
+        &Dash,
+        &Score,
+    )>,
+    pickup_query: Query<&WeaponPickup>,
+    bullet_query: Query<(&Rollback, &Position), With<Bullet>>,
+) {
+    let mut hasher = Fnv::default();
+    hasher.write(round.number);
+    hasher.write(round.frames_left);
+    hasher.write_u64(rng.state());
+
+    let mut players: Vec<_> = player_query.iter().collect();
+    players.sort_by_key(|(player, ..)| player.handle());
+    for (_, position, health, cooldown, weapon, dash, score) in players {
+        hasher.write_vec2(position.0);
+        hasher.write(health.current);
+        hasher.write(cooldown.frames_left);
+        hasher.write(weapon.index as u32);
+        hasher.write(weapon.ammo);
+        hasher.write(dash.frames_left);
+        hasher.write(dash.cooldown_frames_left);
+        hasher.write(dash.invulnerable_frames_left);
+        hasher.write(score.kills);
+        hasher.write(score.deaths);
+    }
+    let mut pickups: Vec<_> = pickup_query.iter().collect();
+    pickups.sort_by_key(|pickup| pickup.spawn);
+    for pickup in pickups {
+        hasher.write(pickup.weapon as u32);
+        hasher.write(pickup.respawn_frames_left);
+    }
+    let mut bullets: Vec<_> = bullet_query.iter().collect();
+    bullets.sort_by_key(|(rollback, _)| rollback.id());
+    for (_, position) in bullets {
+        hasher.write_vec2(position.0);
+    }
+
+    let frame = frame_count.frame;
+    detector.checksums.insert(frame, hasher.checksum());
+    detector
+        .checksums
+        .retain(|checksum_frame, _| *checksum_frame + CHECKSUM_HISTORY > frame);
+}
+
+/// FNV-1a, as the checksum has to be the same on every platform
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv {
+    fn write(&mut self, value: u32) {
+        for byte in value.to_le_bytes() {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(value as u32);
+        self.write((value >> 32) as u32);
+    }
+
+    fn write_vec2(&mut self, value: Vec2) {
+        self.write(value.x.to_bits());
+        self.write(value.y.to_bits());
+    }
+
+    fn checksum(&self) -> u64 {
+        self.0
+    }
+}
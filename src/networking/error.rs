@@ -0,0 +1,91 @@
+use crate::networking::handshake::HandshakeError;
+use ggrs::{GGRSError, PlayerHandle};
+use std::{error::Error, fmt, io, net::AddrParseError};
+
+/// Everything that can go wrong while setting up or running a networked session.
+/// Send it as an event to end the session and show the error in the menu.
+#[derive(Debug)]
+pub enum NetworkingError {
+    #[cfg(not(target_arch = "wasm32"))]
+    Arguments(clap::Error),
+    PlayerCount {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidAddress {
+        address: String,
+        source: AddrParseError,
+    },
+    Socket(io::Error),
+    Handshake(HandshakeError),
+    Session(GGRSError),
+    /// A peer sent input that cannot have been created by a compatible client
+    ProtocolViolation {
+        handle: PlayerHandle,
+        reason: String,
+    },
+}
+
+impl fmt::Display for NetworkingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            NetworkingError::Arguments(error) => write!(f, "Invalid arguments: {}", error),
+            NetworkingError::PlayerCount { expected, actual } => write!(
+                f,
+                "Expected {} players, but {} were given",
+                expected, actual
+            ),
+            NetworkingError::InvalidAddress { address, source } => {
+                write!(f, "Invalid address \"{}\": {}", address, source)
+            }
+            NetworkingError::Socket(error) => write!(f, "Failed to open socket: {}", error),
+            NetworkingError::Handshake(error) => write!(f, "{}", error),
+            NetworkingError::Session(error) => write!(f, "Failed to start session: {}", error),
+            NetworkingError::ProtocolViolation { handle, reason } => write!(
+                f,
+                "Player {} sent invalid data, so the match was ended: {}",
+                handle, reason
+            ),
+        }
+    }
+}
+
+impl Error for NetworkingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            NetworkingError::Arguments(error) => Some(error),
+            NetworkingError::InvalidAddress { source, .. } => Some(source),
+            NetworkingError::Socket(error) => Some(error),
+            NetworkingError::Handshake(error) => Some(error),
+            NetworkingError::Session(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<clap::Error> for NetworkingError {
+    fn from(error: clap::Error) -> Self {
+        NetworkingError::Arguments(error)
+    }
+}
+
+impl From<io::Error> for NetworkingError {
+    fn from(error: io::Error) -> Self {
+        NetworkingError::Socket(error)
+    }
+}
+
+impl From<HandshakeError> for NetworkingError {
+    fn from(error: HandshakeError) -> Self {
+        NetworkingError::Handshake(error)
+    }
+}
+
+impl From<GGRSError> for NetworkingError {
+    fn from(error: GGRSError) -> Self {
+        NetworkingError::Session(error)
+    }
+}
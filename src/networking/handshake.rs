@@ -0,0 +1,243 @@
+use crate::config::{FPS, INPUT_DELAY, MAX_PREDICTION_WINDOW};
+use crate::networking::protocol::PROTOCOL_VERSION;
+use crate::profile::{PlayerColor, Profile, MAX_NAME_BYTES};
+use bytemuck::{Pod, Zeroable};
+use std::{error::Error, fmt, mem::size_of};
+
+/// Hash over the game's sources, calculated in `build.rs`
+const GAME_HASH: &str = env!("GAME_HASH");
+
+/// Sent to every peer before a GGRS session is started.
+/// A session is only started once all peers agree on every field, except for the sender's seed and profile.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Pod, Zeroable)]
+pub struct Handshake {
+    pub game_hash: u64,
+    /// See [`crate::tuning::Tuning::hash`]
+    pub tuning_hash: u64,
+    /// Random part of the [`crate::rng::MatchSeed`], which combines the seeds of all peers
+    pub seed: u64,
+    pub protocol_version: u16,
+    pub fps: u16,
+    pub num_players: u16,
+    pub input_delay: u16,
+    pub max_prediction_window: u16,
+    /// 1 if the sender has already received our handshake, 0 otherwise
+    pub acknowledged: u16,
+    _padding: u32,
+    /// UTF-8 encoded name of the sender, padded with zeros
+    name: [u8; MAX_NAME_BYTES],
+    color: u8,
+    _profile_padding: [u8; 7],
+}
+
+/// Sent in front of every [`Handshake`].
+/// Its layout must never change, so that peers can tell they are incompatible even if their handshakes differ.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Pod, Zeroable)]
+struct HandshakeHeader {
+    protocol_version: u16,
+    /// Size of the handshake following the header in bytes
+    length: u16,
+}
+
+impl Handshake {
+    pub fn local(num_players: usize, tuning_hash: u64, seed: u64, profile: &Profile) -> Self {
+        let mut name = [0; MAX_NAME_BYTES];
+        let name_bytes = profile.name.as_bytes();
+        let name_len = name_bytes.len().min(MAX_NAME_BYTES);
+        name[..name_len].copy_from_slice(&name_bytes[..name_len]);
+        Self {
+            game_hash: GAME_HASH.parse().expect("Invalid game hash"),
+            tuning_hash,
+            seed,
+            protocol_version: PROTOCOL_VERSION,
+            fps: FPS as u16,
+            num_players: num_players as u16,
+            input_delay: INPUT_DELAY as u16,
+            max_prediction_window: MAX_PREDICTION_WINDOW as u16,
+            acknowledged: 0,
+            _padding: 0,
+            name,
+            color: profile.color.index(),
+            _profile_padding: [0; 7],
+        }
+    }
+
+    /// Profile of the sender. As it comes from a peer, it is sanitised before use.
+    pub fn profile(&self) -> Profile {
+        let name_len = self
+            .name
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(MAX_NAME_BYTES);
+        Profile::new(
+            &String::from_utf8_lossy(&self.name[..name_len]),
+            PlayerColor::from_index(self.color),
+        )
+    }
+
+    /// The handshake as it is sent over the network, preceded by a [`HandshakeHeader`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = HandshakeHeader {
+            protocol_version: self.protocol_version,
+            length: size_of::<Handshake>() as u16,
+        };
+        let mut bytes = bytemuck::bytes_of(&header).to_vec();
+        bytes.extend_from_slice(bytemuck::bytes_of(self));
+        bytes
+    }
+
+    /// Reads a handshake sent by a peer.
+    /// The header is checked first, so a peer with a different protocol version gets a proper error
+    /// even if the rest of its handshake looks nothing like ours.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, HandshakeError> {
+        let header_size = size_of::<HandshakeHeader>();
+        if bytes.len() < header_size {
+            return Err(HandshakeError::Layout {
+                expected: size_of::<Handshake>(),
+                actual: bytes.len(),
+            });
+        }
+        let (header, payload) = bytes.split_at(header_size);
+        let header: HandshakeHeader = bytemuck::pod_read_unaligned(header);
+        if header.protocol_version != PROTOCOL_VERSION {
+            return Err(HandshakeError::ProtocolVersion {
+                local: PROTOCOL_VERSION,
+                remote: header.protocol_version,
+            });
+        }
+        if header.length as usize != size_of::<Handshake>()
+            || payload.len() != size_of::<Handshake>()
+        {
+            return Err(HandshakeError::Layout {
+                expected: size_of::<Handshake>(),
+                actual: payload.len(),
+            });
+        }
+        Ok(bytemuck::pod_read_unaligned(payload))
+    }
+
+    pub fn acknowledging(self, acknowledged: bool) -> Self {
+        Self {
+            acknowledged: acknowledged as u16,
+            ..self
+        }
+    }
+
+    pub fn is_acknowledged(&self) -> bool {
+        self.acknowledged != 0
+    }
+
+    /// Checks whether a session with a peer that sent us `remote` would stay in sync
+    pub fn verify(&self, remote: &Handshake) -> Result<(), HandshakeError> {
+        if self.protocol_version != remote.protocol_version {
+            return Err(HandshakeError::ProtocolVersion {
+                local: self.protocol_version,
+                remote: remote.protocol_version,
+            });
+        }
+        if self.game_hash != remote.game_hash {
+            return Err(HandshakeError::GameVersion);
+        }
+        if self.tuning_hash != remote.tuning_hash {
+            return Err(HandshakeError::Tuning);
+        }
+        if self.fps != remote.fps
+            || self.num_players != remote.num_players
+            || self.input_delay != remote.input_delay
+            || self.max_prediction_window != remote.max_prediction_window
+        {
+            return Err(HandshakeError::SessionSettings);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    ProtocolVersion {
+        local: u16,
+        remote: u16,
+    },
+    /// The peer's handshake has a different size than ours, although it claims the same protocol version
+    Layout {
+        expected: usize,
+        actual: usize,
+    },
+    GameVersion,
+    Tuning,
+    SessionSettings,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::ProtocolVersion { local, remote } => write!(
+                f,
+                "Incompatible network protocol: you are on version {}, your opponent on version {}",
+                local, remote
+            ),
+            HandshakeError::Layout { expected, actual } => write!(
+                f,
+                "Incompatible handshake: expected {} bytes, your opponent sent {}",
+                expected, actual
+            ),
+            HandshakeError::GameVersion => write!(
+                f,
+                "Your opponent is running a different version of the game"
+            ),
+            HandshakeError::Tuning => {
+                write!(f, "Your opponent is using different gameplay tuning")
+            }
+            HandshakeError::SessionSettings => {
+                write!(f, "Your opponent is using different session settings")
+            }
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> Handshake {
+        Handshake::local(2, 0, 0, &Profile::default())
+    }
+
+    #[test]
+    fn handshake_survives_the_wire() {
+        let handshake = handshake();
+        assert_eq!(Handshake::from_bytes(&handshake.to_bytes()), Ok(handshake));
+    }
+
+    #[test]
+    fn other_protocol_versions_are_rejected_before_the_layout() {
+        let mut bytes = handshake().to_bytes();
+        // a future handshake with a different version and more fields
+        bytes[..2].copy_from_slice(&(PROTOCOL_VERSION + 1).to_ne_bytes());
+        bytes.extend_from_slice(&[0; 8]);
+        assert_eq!(
+            Handshake::from_bytes(&bytes),
+            Err(HandshakeError::ProtocolVersion {
+                local: PROTOCOL_VERSION,
+                remote: PROTOCOL_VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_handshakes_are_rejected() {
+        let bytes = handshake().to_bytes();
+        assert!(matches!(
+            Handshake::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(HandshakeError::Layout { .. })
+        ));
+        assert!(matches!(
+            Handshake::from_bytes(&bytes[..1]),
+            Err(HandshakeError::Layout { .. })
+        ));
+    }
+}
@@ -0,0 +1,213 @@
+//! Runs the rollback schedule without rendering or input devices, driven by scripted inputs.
+//! Used by the tests and the rollback benchmark.
+
+use crate::actions::Actions;
+use crate::config::{FPS, INPUT_DELAY, MAX_PREDICTION_WINDOW, NUM_PLAYERS};
+use crate::networking::add_rollback_simulation;
+use crate::networking::desync::ChecksumMailbox;
+use crate::networking::handshake::Handshake;
+use crate::networking::loopback::LoopbackNetwork;
+use crate::networking::protocol::{InputFlags, InputProtocol};
+use crate::networking::simulator::{NetworkConditions, SimulatedSocket};
+use crate::networking::socket::{GameSocket, IdleSocket, PacketSocket};
+use crate::networking::FrameCount;
+use crate::pause::Pause;
+use crate::player::{simulation_bundle, Player};
+use crate::profile::Profile;
+use crate::round::Round;
+use crate::tuning::Tuning;
+use crate::weapon::pickup_bundle;
+use crate::GameState;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_ggrs::{Rollback, RollbackIdProvider, SessionType};
+use ggrs::{Config, PlayerHandle, PlayerType, SessionBuilder};
+use std::collections::VecDeque;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct HeadlessConfig;
+impl Config for HeadlessConfig {
+    type Input = InputProtocol;
+    type State = u8;
+    /// Index of the peer on the [`LoopbackNetwork`]
+    type Address = usize;
+}
+
+/// Inputs handed to GGRS for each local player, one per frame. Once a script runs out, players stand still.
+#[derive(Default, Clone)]
+pub struct InputScript(HashMap<PlayerHandle, VecDeque<InputProtocol>>);
+
+impl InputScript {
+    /// Appends `input` for `frames` frames to the script of `handle`
+    pub fn with(mut self, handle: PlayerHandle, frames: usize, input: InputProtocol) -> Self {
+        self.0
+            .entry(handle)
+            .or_default()
+            .extend(std::iter::repeat(input).take(frames));
+        self
+    }
+
+    /// Only the inputs of `handle`, for the app in which they are local
+    fn only(&self, handle: PlayerHandle) -> Self {
+        let mut script = InputScript::default();
+        if let Some(inputs) = self.0.get(&handle) {
+            script.0.insert(handle, inputs.clone());
+        }
+        script
+    }
+}
+
+fn scripted_input(handle: In<PlayerHandle>, mut script: ResMut<InputScript>) -> InputProtocol {
+    script
+        .0
+        .get_mut(&handle.0)
+        .and_then(VecDeque::pop_front)
+        .unwrap_or_else(|| InputFlags::empty().into())
+}
+
+/// An app with just the rollback simulation and all players, still without a session
+fn headless_app(script: InputScript) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_state(GameState::Playing)
+        .init_resource::<Pause>()
+        .init_resource::<Vec<Actions>>()
+        .init_resource::<Round>()
+        .init_resource::<Tuning>()
+        .insert_resource(script);
+    add_rollback_simulation::<HeadlessConfig, _>(&mut app, scripted_input);
+
+    let tuning = app.world.resource::<Tuning>().clone();
+    for handle in 0..NUM_PLAYERS {
+        let id = app
+            .world
+            .get_resource_or_insert_with(RollbackIdProvider::default)
+            .next_id();
+        app.world
+            .spawn()
+            .insert_bundle(simulation_bundle(Player::new(handle), &tuning))
+            .insert(Rollback::new(id));
+    }
+    for spawn in 0..tuning.pickups.len() {
+        let id = app
+            .world
+            .get_resource_or_insert_with(RollbackIdProvider::default)
+            .next_id();
+        app.world
+            .spawn()
+            .insert_bundle(pickup_bundle(spawn, &tuning))
+            .insert(Rollback::new(id));
+    }
+    app
+}
+
+/// A single app simulating every player.
+/// Every frame, it rolls back `check_distance` frames and checks that resimulating them leads to the same state.
+pub fn sync_test_app(script: InputScript, check_distance: usize) -> App {
+    let mut session_builder = SessionBuilder::<HeadlessConfig>::new()
+        .with_num_players(NUM_PLAYERS)
+        .with_max_prediction_window(MAX_PREDICTION_WINDOW)
+        .with_check_distance(check_distance);
+    for handle in 0..NUM_PLAYERS {
+        session_builder = session_builder
+            .add_player(PlayerType::Local, handle)
+            .expect("Invalid player handle");
+    }
+    let session = session_builder
+        .start_synctest_session()
+        .expect("Failed to start sync test session");
+
+    let mut app = headless_app(script);
+    app.insert_resource(session)
+        .insert_resource(SessionType::SyncTestSession);
+    app
+}
+
+/// One app per player, connected through a [`LoopbackNetwork`] with the given conditions
+pub fn p2p_apps(script: InputScript, conditions: NetworkConditions) -> Vec<App> {
+    let network = LoopbackNetwork::default();
+    let tuning_hash = Tuning::default().hash();
+    (0..NUM_PLAYERS)
+        .map(|local_handle| {
+            let mut session_builder = SessionBuilder::<HeadlessConfig>::new()
+                .with_num_players(NUM_PLAYERS)
+                .with_input_delay(INPUT_DELAY)
+                .with_max_prediction_window(MAX_PREDICTION_WINDOW)
+                .with_fps(FPS)
+                .expect("Invalid FPS");
+            for handle in 0..NUM_PLAYERS {
+                let player_type = if handle == local_handle {
+                    PlayerType::Local
+                } else {
+                    PlayerType::Remote(handle)
+                };
+                session_builder = session_builder
+                    .add_player(player_type, handle)
+                    .expect("Invalid player handle");
+            }
+            let socket: Box<dyn PacketSocket<usize>> = if conditions.is_perfect() {
+                Box::new(network.socket(local_handle))
+            } else {
+                Box::new(SimulatedSocket::new(
+                    Box::new(network.socket(local_handle)),
+                    conditions.clone(),
+                ))
+            };
+            let handshake = Handshake::local(NUM_PLAYERS, tuning_hash, 0, &Profile::default());
+            let checksum_peers = (0..NUM_PLAYERS)
+                .filter(|handle| *handle != local_handle)
+                .map(|handle| (handle, handle))
+                .collect();
+            let checksums = ChecksumMailbox::default();
+            let socket = GameSocket::new(socket, handshake)
+                .with_checksums(checksum_peers, checksums.clone());
+            let session = session_builder
+                .start_p2p_session(socket)
+                .expect("Failed to start P2P session");
+
+            // handed to GGRS while frames are skipped, see `crate::networking::sync::FrameSkipStage`
+            let idle_session = SessionBuilder::<HeadlessConfig>::new()
+                .with_num_players(NUM_PLAYERS)
+                .start_spectator_session((local_handle + 1) % NUM_PLAYERS, IdleSocket);
+
+            let mut app = headless_app(script.only(local_handle));
+            app.insert_resource(session)
+                .insert_resource(idle_session)
+                .insert_resource(checksums)
+                .insert_resource(SessionType::P2PSession);
+            app
+        })
+        .collect()
+}
+
+/// Updates all apps until each of them simulated `frame`.
+/// The GGRS stage advances in real time, so this takes about `frame / FPS` seconds.
+/// Returns `false` if that did not happen within `timeout`.
+pub fn run_until_frame(apps: &mut [App], frame: u32, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while apps
+        .iter()
+        .any(|app| app.world.resource::<FrameCount>().frame < frame)
+    {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        for app in apps.iter_mut() {
+            app.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+/// Updates all apps until `until`
+pub fn run_until(apps: &mut [App], until: Instant) {
+    while Instant::now() < until {
+        for app in apps.iter_mut() {
+            app.update();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
@@ -1,24 +1,54 @@
+use bevy::math::Vec2;
 use bitflags::bitflags;
 use bytemuck::{Pod, Zeroable};
 use ggrs::PlayerHandle;
+use std::f32::consts::TAU;
+
+/// Version of the [`InputProtocol`] layout.
+/// Bump this whenever the layout or meaning of the transmitted bytes changes,
+/// so that clients with different layouts never end up in the same match.
+pub const PROTOCOL_VERSION: u16 = 9;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Pod, Zeroable)]
 pub struct InputProtocol {
     /// This is the number of bytes one peer’s input is.
-    /// In our case, the input consists of four direction buttons, and eventually the fire button as well.
+    /// In our case, the input consists of four direction buttons, the fire and dash buttons and whether the player is aiming.
     /// This means it fits easily within a single byte:
     pub input: u8,
+    /// The aim angle quantised to 256 steps, see [`quantise_aim`].
+    /// Only meaningful if [`InputFlags::AIM`] is set in `input`.
+    pub aim: u8,
 }
 
 impl InputProtocol {
     pub fn new(input: InputFlags) -> Self {
         InputProtocol {
             input: input.bits(),
+            aim: 0,
+        }
+    }
+
+    pub fn with_aim(input: InputFlags, aim: Vec2) -> Self {
+        InputProtocol {
+            input: (input | InputFlags::AIM).bits(),
+            aim: quantise_aim(aim),
         }
     }
 }
 
+/// Quantises a direction into an angle of one of 256 steps, starting at the positive x axis and going counterclockwise.
+pub fn quantise_aim(direction: Vec2) -> u8 {
+    let turns = (direction.y.atan2(direction.x) / TAU).rem_euclid(1.0);
+    ((turns * 256.0).round() as u16 % 256) as u8
+}
+
+/// Inverse of [`quantise_aim`]. Returns a normalized direction.
+pub fn dequantise_aim(aim: u8) -> Vec2 {
+    let angle = aim as f32 / 256.0 * TAU;
+    Vec2::new(angle.cos(), angle.sin())
+}
+
 bitflags! {
     pub struct InputFlags: u8 {
         const UP = 1 << 0;
@@ -26,6 +56,8 @@ bitflags! {
         const LEFT = 1 << 2;
         const RIGHT = 1 << 3;
         const FIRE = 1 << 4;
+        const AIM = 1 << 5;
+        const DASH = 1 << 6;
     }
 }
 
@@ -0,0 +1,105 @@
+use crate::networking::socket::PacketSocket;
+use bevy::log;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// How [`SimulatedSocket`] degrades the connection. Probabilities are between 0 and 1.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Delay added to every packet
+    pub latency: Duration,
+    /// Maximum random delay added on top of the latency
+    pub jitter: Duration,
+    pub packet_loss: f64,
+    pub duplication: f64,
+    /// Probability of a packet being held back long enough to be overtaken by later ones
+    pub reordering: f64,
+}
+
+impl NetworkConditions {
+    pub fn is_perfect(&self) -> bool {
+        *self == NetworkConditions::default()
+    }
+}
+
+/// Packets held back for reordering are delayed by this on top of their regular delay
+const REORDERING_DELAY: Duration = Duration::from_millis(50);
+
+struct DelayedPacket<A> {
+    due: Instant,
+    addr: A,
+    packet: Vec<u8>,
+}
+
+/// Wraps another socket and applies [`NetworkConditions`] to every packet we send,
+/// so bad connections can be reproduced on localhost.
+/// Only outgoing packets are affected; run every peer with the same conditions for a symmetric connection.
+pub struct SimulatedSocket<A> {
+    inner: Box<dyn PacketSocket<A>>,
+    conditions: NetworkConditions,
+    delayed: Vec<DelayedPacket<A>>,
+}
+
+impl<A> SimulatedSocket<A> {
+    pub fn new(inner: Box<dyn PacketSocket<A>>, conditions: NetworkConditions) -> Self {
+        log::info!("Simulating network conditions: {:?}", conditions);
+        Self {
+            inner,
+            conditions,
+            delayed: Vec::new(),
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        let mut rng = rand::thread_rng();
+        let mut delay = self.conditions.latency;
+        if !self.conditions.jitter.is_zero() {
+            delay += self.conditions.jitter.mul_f64(rng.gen());
+        }
+        if rng.gen_bool(self.conditions.reordering) {
+            delay += REORDERING_DELAY;
+        }
+        delay
+    }
+
+    /// Sends all packets whose delay is over
+    fn flush(&mut self) {
+        let now = Instant::now();
+        let (due, delayed) = self
+            .delayed
+            .drain(..)
+            .partition::<Vec<_>, _>(|packet| packet.due <= now);
+        self.delayed = delayed;
+        for packet in due {
+            self.inner.send_packet(&packet.packet, &packet.addr);
+        }
+    }
+}
+
+impl<A: Clone + Send + Sync> PacketSocket<A> for SimulatedSocket<A> {
+    fn send_packet(&mut self, packet: &[u8], addr: &A) {
+        let mut rng = rand::thread_rng();
+        if !rng.gen_bool(self.conditions.packet_loss) {
+            let copies = if rng.gen_bool(self.conditions.duplication) {
+                2
+            } else {
+                1
+            };
+            for _ in 0..copies {
+                let due = Instant::now() + self.delay();
+                self.delayed.push(DelayedPacket {
+                    due,
+                    addr: addr.clone(),
+                    packet: packet.to_vec(),
+                });
+            }
+        }
+        self.flush();
+    }
+
+    fn receive_packets(&mut self) -> Vec<(A, Vec<u8>)> {
+        // GGRS polls for packets every frame, even when it has nothing to send
+        self.flush();
+        self.inner.receive_packets()
+    }
+}
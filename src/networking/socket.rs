@@ -0,0 +1,158 @@
+use crate::networking::desync::ChecksumMailbox;
+use crate::networking::handshake::{Handshake, HandshakeError};
+use bevy::log;
+use ggrs::{Message, NonBlockingSocket, PlayerHandle};
+
+/// A socket that can send and receive raw packets. Implemented per platform.
+pub trait PacketSocket<A>: Send + Sync {
+    fn send_packet(&mut self, packet: &[u8], addr: &A);
+    fn receive_packets(&mut self) -> Vec<(A, Vec<u8>)>;
+}
+
+/// The first byte of every packet tells us what kind of packet it is
+#[repr(u8)]
+pub enum Channel {
+    Ggrs = 0,
+    Handshake = 1,
+    Checksum = 2,
+}
+
+/// Socket handed to GGRS.
+/// Besides the GGRS messages it also carries our [`Handshake`]s, which are exchanged before the session is started,
+/// and the checksums of the [`crate::networking::desync::DesyncDetector`] once it is running.
+pub struct GameSocket<A> {
+    inner: Box<dyn PacketSocket<A>>,
+    handshake: Handshake,
+    /// Peers we exchange checksums with, and the player each of them is
+    checksum_peers: Vec<(A, PlayerHandle)>,
+    checksums: ChecksumMailbox,
+}
+
+impl<A> GameSocket<A> {
+    pub fn new(inner: Box<dyn PacketSocket<A>>, handshake: Handshake) -> Self {
+        Self {
+            inner,
+            handshake,
+            checksum_peers: Vec::new(),
+            checksums: ChecksumMailbox::default(),
+        }
+    }
+
+    /// Exchanges the checksums in `mailbox` with `peers` while the session is running
+    pub fn with_checksums(
+        mut self,
+        peers: Vec<(A, PlayerHandle)>,
+        mailbox: ChecksumMailbox,
+    ) -> Self {
+        self.checksum_peers = peers;
+        self.checksums = mailbox;
+        self
+    }
+
+    pub fn handshake(&self) -> Handshake {
+        self.handshake
+    }
+
+    pub fn send_handshake(&mut self, handshake: Handshake, addr: &A) {
+        let mut packet = vec![Channel::Handshake as u8];
+        packet.extend(handshake.to_bytes());
+        self.inner.send_packet(&packet, addr);
+    }
+
+    /// Receives all pending handshakes, or the reason why a peer's handshake could not be read.
+    /// Any GGRS messages received in the meantime are dropped. GGRS will resend them once the session is running.
+    pub fn receive_handshakes(&mut self) -> Vec<(A, Result<Handshake, HandshakeError>)> {
+        self.inner
+            .receive_packets()
+            .into_iter()
+            .filter_map(|(addr, packet)| {
+                parse_handshake(&packet).map(|handshake| (addr, handshake))
+            })
+            .collect()
+    }
+}
+
+fn parse_handshake(packet: &[u8]) -> Option<Result<Handshake, HandshakeError>> {
+    match packet.split_first() {
+        Some((&channel, payload)) if channel == Channel::Handshake as u8 => {
+            Some(Handshake::from_bytes(payload))
+        }
+        _ => None,
+    }
+}
+
+/// Frame and checksum, as sent by [`GameSocket::receive_all_messages`]
+fn parse_checksum(payload: &[u8]) -> Option<(u32, u64)> {
+    let frame = payload.get(..4)?.try_into().ok()?;
+    let checksum = payload.get(4..)?.try_into().ok()?;
+    Some((u32::from_le_bytes(frame), u64::from_le_bytes(checksum)))
+}
+
+impl<A> NonBlockingSocket<A> for GameSocket<A>
+where
+    A: Clone + PartialEq + Eq + std::hash::Hash + Send + Sync,
+{
+    fn send_to(&mut self, msg: &Message, addr: &A) {
+        let mut packet = vec![Channel::Ggrs as u8];
+        bincode::serialize_into(&mut packet, msg).expect("Failed to serialize GGRS message");
+        self.inner.send_packet(&packet, addr);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(A, Message)> {
+        for (frame, checksum) in self.checksums.take_outgoing() {
+            let mut packet = vec![Channel::Checksum as u8];
+            packet.extend(frame.to_le_bytes());
+            packet.extend(checksum.to_le_bytes());
+            for (addr, _) in &self.checksum_peers {
+                self.inner.send_packet(&packet, addr);
+            }
+        }
+
+        let mut messages = Vec::new();
+        for (addr, packet) in self.inner.receive_packets() {
+            match packet.split_first() {
+                Some((&channel, payload)) if channel == Channel::Ggrs as u8 => {
+                    match bincode::deserialize(payload) {
+                        Ok(message) => messages.push((addr, message)),
+                        Err(error) => log::warn!("Dropping malformed GGRS message: {}", error),
+                    }
+                }
+                Some((&channel, _)) if channel == Channel::Handshake as u8 => {
+                    // The peer has not yet received our acknowledgement, so they're still waiting for us
+                    let handshake = self.handshake.acknowledging(true);
+                    self.send_handshake(handshake, &addr);
+                }
+                Some((&channel, payload)) if channel == Channel::Checksum as u8 => {
+                    let handle = self
+                        .checksum_peers
+                        .iter()
+                        .find(|(peer, _)| *peer == addr)
+                        .map(|(_, handle)| *handle);
+                    match (handle, parse_checksum(payload)) {
+                        (Some(handle), Some((frame, checksum))) => {
+                            self.checksums.deliver(handle, frame, checksum)
+                        }
+                        _ => log::warn!("Dropping checksum that is malformed or not from a peer"),
+                    }
+                }
+                _ => log::warn!("Dropping packet on unknown channel"),
+            }
+        }
+        messages
+    }
+}
+
+/// A socket that never sends or receives anything.
+/// Used for the idle session that GGRS gets while we skip frames, see [`crate::networking::sync`].
+pub struct IdleSocket;
+
+impl<A> NonBlockingSocket<A> for IdleSocket
+where
+    A: Clone + PartialEq + Eq + std::hash::Hash + Send + Sync,
+{
+    fn send_to(&mut self, _msg: &Message, _addr: &A) {}
+
+    fn receive_all_messages(&mut self) -> Vec<(A, Message)> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,239 @@
+use crate::config::FPS;
+use crate::networking::{FrameCount, PlatformConfig};
+use bevy::{
+    ecs::schedule::Stage,
+    log,
+    prelude::*,
+    utils::{Duration, Instant},
+};
+use bevy_ggrs::SessionType;
+use ggrs::{Config, GGRSEvent, P2PSession, SessionState, SpectatorSession};
+use std::marker::PhantomData;
+
+/// The simulation counts as stalled if no frame was simulated for this long while the session is running.
+/// GGRS stops advancing once we are too far ahead of a peer's confirmed inputs.
+const STALL_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Why the match is currently not running smoothly. Not rolled back.
+#[derive(Default, Debug)]
+pub struct SyncStatus {
+    pub waiting: Option<Waiting>,
+    /// Frames we are ahead of our peers, as estimated by GGRS
+    pub frames_ahead: i32,
+    /// Until when we skip frames, because GGRS recommended waiting for our peers to catch up
+    skip_until: Option<Instant>,
+    last_frame: u32,
+    last_progress: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waiting {
+    /// Still exchanging the first messages with our peers
+    Synchronizing { count: u32, total: u32 },
+    /// A peer stopped answering, the session ends if they don't come back in time
+    Interrupted { disconnect_at: Instant },
+    /// Too far ahead of a peer's inputs to predict any further
+    Stalled { since: Instant },
+}
+
+impl Waiting {
+    pub fn describe(&self) -> String {
+        match self {
+            Waiting::Synchronizing { count, total } => {
+                format!("Synchronizing with peer... {}/{}", count, total)
+            }
+            Waiting::Interrupted { disconnect_at } => format!(
+                "Connection to peer interrupted, disconnecting in {}s",
+                disconnect_at
+                    .saturating_duration_since(Instant::now())
+                    .as_secs()
+                    + 1
+            ),
+            Waiting::Stalled { .. } => "Waiting for peer...".to_string(),
+        }
+    }
+}
+
+impl SyncStatus {
+    /// Follows a `WaitRecommendation` of GGRS. A newer recommendation replaces the previous one.
+    pub fn recommend_wait(&mut self, skip_frames: u32, now: Instant) {
+        let frame = Duration::from_secs_f64(1. / FPS as f64);
+        self.skip_until = Some(now + frame * skip_frames);
+    }
+
+    /// Whether the frames of the update at `now` should be skipped.
+    /// The recommendation is consumed once its frames have been skipped.
+    pub fn should_skip(&mut self, now: Instant) -> bool {
+        match self.skip_until {
+            Some(until) if now < until => true,
+            _ => {
+                self.skip_until = None;
+                false
+            }
+        }
+    }
+
+    pub fn is_skipping_frames(&self) -> bool {
+        self.skip_until.is_some()
+    }
+}
+
+pub fn reset_sync_status(mut status: ResMut<SyncStatus>) {
+    *status = default();
+}
+
+/// Logs the events of the GGRS session and keeps track of why we might be waiting for our peers
+pub fn update_sync_status(
+    session: Option<ResMut<P2PSession<PlatformConfig>>>,
+    frame_count: Res<FrameCount>,
+    mut status: ResMut<SyncStatus>,
+) {
+    let mut session = match session {
+        Some(session) => session,
+        None => return,
+    };
+    let events: Vec<_> = session.events().collect();
+    for event in events {
+        match event {
+            GGRSEvent::Synchronizing { addr, total, count } => {
+                log::info!("Synchronizing with {:?}: {}/{}", addr, count, total);
+                status.waiting = Some(Waiting::Synchronizing { count, total });
+            }
+            GGRSEvent::Synchronized { addr } => {
+                log::info!("Synchronized with {:?}", addr);
+                status.waiting = None;
+            }
+            GGRSEvent::NetworkInterrupted {
+                addr,
+                disconnect_timeout,
+            } => {
+                log::warn!(
+                    "Connection to {:?} interrupted, disconnecting in {}ms",
+                    addr,
+                    disconnect_timeout
+                );
+                status.waiting = Some(Waiting::Interrupted {
+                    disconnect_at: Instant::now()
+                        + Duration::from_millis(disconnect_timeout as u64),
+                });
+            }
+            GGRSEvent::NetworkResumed { addr } => {
+                log::info!("Connection to {:?} resumed", addr);
+                status.waiting = None;
+            }
+            GGRSEvent::Disconnected { addr } => {
+                log::warn!("Disconnected from {:?}", addr);
+                status.waiting = None;
+            }
+            GGRSEvent::WaitRecommendation { skip_frames } => {
+                log::info!(
+                    "{} frames ahead of our peers, skipping {} frames as recommended by GGRS",
+                    session.frames_ahead(),
+                    skip_frames
+                );
+                status.recommend_wait(skip_frames, Instant::now());
+            }
+        }
+    }
+
+    status.frames_ahead = session.frames_ahead();
+
+    if session.current_state() != SessionState::Running {
+        return;
+    }
+    let now = Instant::now();
+    if frame_count.frame != status.last_frame {
+        if let Some(Waiting::Stalled { since }) = status.waiting {
+            log::info!(
+                "Simulation resumed after waiting {:?} for peers",
+                now - since
+            );
+            status.waiting = None;
+        }
+        status.last_frame = frame_count.frame;
+        status.last_progress = Some(now);
+        return;
+    }
+    let last_progress = *status.last_progress.get_or_insert(now);
+    if status.waiting.is_none() && now - last_progress > STALL_THRESHOLD {
+        log::warn!(
+            "Simulation stalled at frame {}, waiting for peer inputs",
+            frame_count.frame
+        );
+        status.waiting = Some(Waiting::Stalled {
+            since: last_progress,
+        });
+    }
+}
+
+/// Wraps the GGRS stage, which has no way to skip frames of a running session, to skip the frames GGRS recommends.
+/// Not running the GGRS stage would not do, as it catches up on the time it did not run.
+/// Instead, it is run with an idle spectator session that never finishes synchronizing, so its ticks pass
+/// without a frame, while our [`P2PSession`] is still polled and keeps talking to our peers.
+/// The session is swapped and restored within this stage, so no other system ever sees the idle one.
+pub struct FrameSkipStage<T> {
+    ggrs: Schedule,
+    config: PhantomData<fn() -> T>,
+}
+
+impl<T> FrameSkipStage<T> {
+    /// `ggrs` is the schedule the GGRS plugin added its stage to
+    pub fn new(ggrs: Schedule) -> Self {
+        Self {
+            ggrs,
+            config: PhantomData,
+        }
+    }
+}
+
+impl<T: Config> Stage for FrameSkipStage<T> {
+    fn run(&mut self, world: &mut World) {
+        let skip = world.contains_resource::<SpectatorSession<T>>()
+            && matches!(
+                world.get_resource::<SessionType>(),
+                Some(SessionType::P2PSession)
+            )
+            && world
+                .resource_mut::<SyncStatus>()
+                .should_skip(Instant::now());
+        if !skip {
+            self.ggrs.run(world);
+            return;
+        }
+        world.insert_resource(SessionType::SpectatorSession);
+        self.ggrs.run(world);
+        world.insert_resource(SessionType::P2PSession);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(1000 / FPS as u64);
+
+    #[test]
+    fn recommended_frames_are_skipped_once() {
+        let mut status = SyncStatus::default();
+        let start = Instant::now();
+        assert!(!status.should_skip(start));
+
+        status.recommend_wait(3, start);
+        assert!(status.should_skip(start));
+        assert!(status.should_skip(start + FRAME * 2));
+        assert!(!status.should_skip(start + FRAME * 4));
+        assert!(!status.is_skipping_frames());
+        // consumed, so it doesn't add up with the next recommendation
+        assert!(!status.should_skip(start + FRAME * 2));
+    }
+
+    #[test]
+    fn newer_recommendations_replace_older_ones() {
+        let mut status = SyncStatus::default();
+        let start = Instant::now();
+        status.recommend_wait(10, start);
+        status.recommend_wait(2, start + FRAME);
+        assert!(status.should_skip(start + FRAME * 2));
+        assert!(!status.should_skip(start + FRAME * 4));
+    }
+}
@@ -1,5 +1,7 @@
 mod shared;
 
+pub use shared::PendingSession;
+
 #[cfg(target_arch = "wasm32")]
 mod wasm;
 
@@ -1,19 +1,37 @@
-use std::net::SocketAddr;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
 
-use crate::{networking::protocol::LocalHandles, GameState};
+use crate::{
+    config::NUM_PLAYERS,
+    networking::{
+        error::NetworkingError,
+        loopback::LoopbackNetwork,
+        simulator::{NetworkConditions, SimulatedSocket},
+        socket::PacketSocket,
+    },
+    profile::Profile,
+    tuning::Tuning,
+    GameState,
+};
 
-use super::shared::{self, create_session_builder};
+use super::shared::{self, exchange_handshakes, PendingSession};
 use bevy::{log, prelude::*};
-use bevy_ggrs::SessionType;
 use clap::Parser;
-use ggrs::{Config, PlayerType, SessionBuilder, UdpNonBlockingSocket};
+use ggrs::{Config, PlayerType};
 
 #[derive(Debug, Default)]
 pub struct NativePlugin;
 impl Plugin for NativePlugin {
     fn build(&self, app: &mut App) {
         log::info!("Using native networking plugin");
-        app.add_system_set(SystemSet::on_enter(GameState::Playing).with_system(start_session));
+        app.init_resource::<Option<PendingSession<NativeConfig>>>()
+            .add_system_set(SystemSet::on_update(GameState::Menu).with_system(auto_connect))
+            .add_system_set(SystemSet::on_enter(GameState::Connecting).with_system(start_session))
+            .add_system_set(
+                SystemSet::on_update(GameState::Connecting)
+                    .with_system(exchange_handshakes::<NativeConfig>),
+            );
     }
 }
 
@@ -34,39 +52,149 @@ struct Args {
     local_port: u16,
     #[clap(short, long)]
     players: Vec<String>,
+    /// Start connecting as soon as the menu is shown, used by the local match launcher
+    #[clap(long)]
+    auto_connect: bool,
+    /// Simulated latency of outgoing packets in milliseconds
+    #[clap(long, default_value_t = 0)]
+    latency: u64,
+    /// Maximum simulated random delay on top of the latency in milliseconds
+    #[clap(long, default_value_t = 0)]
+    jitter: u64,
+    /// Probability of dropping an outgoing packet
+    #[clap(long, default_value_t = 0., parse(try_from_str = parse_probability))]
+    packet_loss: f64,
+    /// Probability of sending an outgoing packet twice
+    #[clap(long, default_value_t = 0., parse(try_from_str = parse_probability))]
+    duplication: f64,
+    /// Probability of delaying an outgoing packet long enough for later packets to overtake it
+    #[clap(long, default_value_t = 0., parse(try_from_str = parse_probability))]
+    reordering: f64,
 }
 
-fn start_session(mut commands: Commands) {
-    let args = Args::parse();
+impl Args {
+    fn network_conditions(&self) -> NetworkConditions {
+        NetworkConditions {
+            latency: Duration::from_millis(self.latency),
+            jitter: Duration::from_millis(self.jitter),
+            packet_loss: self.packet_loss,
+            duplication: self.duplication,
+            reordering: self.reordering,
+        }
+    }
+}
+
+fn parse_probability(value: &str) -> Result<f64, String> {
+    let probability: f64 = value.parse().map_err(|error| format!("{}", error))?;
+    if (0. ..=1.).contains(&probability) {
+        Ok(probability)
+    } else {
+        Err(format!("{} is not between 0 and 1", probability))
+    }
+}
+
+/// Maximum size of a single packet we are able to receive
+const RECEIVE_BUFFER_SIZE: usize = 4096;
+
+impl PacketSocket<SocketAddr> for UdpSocket {
+    fn send_packet(&mut self, packet: &[u8], addr: &SocketAddr) {
+        if let Err(error) = self.send_to(packet, addr) {
+            log::warn!("Failed to send packet to {}: {}", addr, error);
+        }
+    }
+
+    fn receive_packets(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut packets = Vec::new();
+        let mut buffer = [0; RECEIVE_BUFFER_SIZE];
+        loop {
+            match self.recv_from(&mut buffer) {
+                Ok((len, addr)) => packets.push((addr, buffer[..len].to_vec())),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return packets,
+                // On windows, a remote that is not yet listening will show up as a reset connection
+                Err(error) if error.kind() == ErrorKind::ConnectionReset => continue,
+                Err(error) => {
+                    log::warn!("Failed to receive packets: {}", error);
+                    return packets;
+                }
+            }
+        }
+    }
+}
+
+/// Only connects automatically the first time the menu is shown, so players can still leave a match
+fn auto_connect(mut connected: Local<bool>, mut state: ResMut<State<GameState>>) {
+    if *connected {
+        return;
+    }
+    *connected = true;
+    if Args::try_parse().map_or(false, |args| args.auto_connect) {
+        state.set(GameState::Connecting).unwrap();
+    }
+}
+
+fn start_session(
+    mut pending_session: ResMut<Option<PendingSession<NativeConfig>>>,
+    tuning: Res<Tuning>,
+    profile: Res<Profile>,
+    mut errors: EventWriter<NetworkingError>,
+) {
+    match create_pending_session(tuning.hash(), profile.clone()) {
+        // the GGRS session is started as soon as all peers agree on the handshake
+        Ok(session) => *pending_session = Some(session),
+        Err(error) => errors.send(error),
+    }
+}
+
+fn create_pending_session(
+    tuning_hash: u64,
+    profile: Profile,
+) -> Result<PendingSession<NativeConfig>, NetworkingError> {
+    let args = Args::try_parse()?;
+    let conditions = args.network_conditions();
     let num_players = args.players.len();
     log::info!("Got args: {:?}", args);
-    assert!(num_players == 2);
+    if num_players != NUM_PLAYERS {
+        return Err(NetworkingError::PlayerCount {
+            expected: NUM_PLAYERS,
+            actual: num_players,
+        });
+    }
 
-    // create a GGRS session
-    let mut p2p_session: SessionBuilder<NativeConfig> = create_session_builder(num_players);
-    let mut handles = Vec::new();
     // add players
-    for (i, player_addr) in args.players.into_iter().enumerate() {
-        // local player
-        if player_addr == "localhost" {
-            p2p_session = p2p_session
-                .add_player(PlayerType::Local, i)
-                .expect("Failed to add local player");
-            handles.push(i);
-        } else {
+    let players = args
+        .players
+        .into_iter()
+        .map(|player_addr| {
+            // local player
+            if player_addr == "localhost" {
+                return Ok(PlayerType::Local);
+            }
             // remote players
-            let remote_addr: SocketAddr = player_addr.parse().unwrap();
-            p2p_session = p2p_session
-                .add_player(PlayerType::Remote(remote_addr), i)
-                .expect("Failed to add remote player");
-        }
-    }
+            match player_addr.parse() {
+                Ok(remote_addr) => Ok(PlayerType::Remote(remote_addr)),
+                Err(source) => Err(NetworkingError::InvalidAddress {
+                    address: player_addr,
+                    source,
+                }),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-    // start the GGRS session
-    let socket = UdpNonBlockingSocket::bind_to_port(args.local_port).unwrap();
-    let session = p2p_session.start_p2p_session(socket).unwrap();
+    let socket: Box<dyn PacketSocket<SocketAddr>> =
+        if players.iter().all(|player| *player == PlayerType::Local) {
+            // nobody to talk to, so there is no need to occupy a port
+            let address = SocketAddr::from(([127, 0, 0, 1], args.local_port));
+            Box::new(LoopbackNetwork::default().socket(address))
+        } else {
+            let socket = UdpSocket::bind(("0.0.0.0", args.local_port))?;
+            socket.set_nonblocking(true)?;
+            Box::new(socket)
+        };
+    let socket: Box<dyn PacketSocket<SocketAddr>> = if conditions.is_perfect() {
+        socket
+    } else {
+        Box::new(SimulatedSocket::new(socket, conditions))
+    };
 
-    commands.insert_resource(session);
-    commands.insert_resource(LocalHandles { handles });
-    commands.insert_resource(SessionType::P2PSession);
+    Ok(PendingSession::new(socket, players, tuning_hash, profile))
 }
@@ -1,20 +1,313 @@
-use crate::config::FPS;
-use ggrs::{Config, SessionBuilder};
-
-use crate::networking::protocol::InputProtocol;
+use crate::config::{FPS, INPUT_DELAY, MAX_PREDICTION_WINDOW};
+use crate::networking::desync::ChecksumMailbox;
+use crate::networking::error::NetworkingError;
+use crate::networking::handshake::Handshake;
+use crate::networking::protocol::{InputProtocol, LocalHandles};
+use crate::networking::socket::{GameSocket, IdleSocket, PacketSocket};
+use crate::profile::{PlayerProfiles, Profile};
+use crate::rng::MatchSeed;
+use crate::GameState;
+use bevy::{
+    log,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bevy_ggrs::SessionType;
+use ggrs::{Config, PlayerType, SessionBuilder};
 
 pub type Input = InputProtocol;
 pub type State = u8;
 
 pub fn create_session_builder<GGRSConfig: Config>(
     num_players: usize,
-) -> SessionBuilder<GGRSConfig> {
-    SessionBuilder::<GGRSConfig>::new()
+) -> Result<SessionBuilder<GGRSConfig>, NetworkingError> {
+    let session_builder = SessionBuilder::<GGRSConfig>::new()
         .with_num_players(num_players)
-        .with_input_delay(3)
-        .with_max_prediction_window(12)
-        .with_catchup_speed(2)
-        .expect("Invalid catchup speed")
-        .with_fps(FPS)
-        .expect("Invalid FPS")
+        .with_input_delay(INPUT_DELAY)
+        .with_max_prediction_window(MAX_PREDICTION_WINDOW)
+        .with_catchup_speed(2)?
+        .with_fps(FPS)?;
+    Ok(session_builder)
+}
+
+/// A session whose players are known, but who have not yet agreed on a [`Handshake`]
+pub struct PendingSession<GGRSConfig: Config> {
+    socket: GameSocket<GGRSConfig::Address>,
+    players: Vec<PlayerType<GGRSConfig::Address>>,
+    local_profile: Profile,
+    /// Peers that sent us a valid handshake, with the handshake they sent
+    received: HashMap<GGRSConfig::Address, Handshake>,
+    /// Peers that know that we received their handshake
+    acknowledged: HashSet<GGRSConfig::Address>,
+}
+
+impl<GGRSConfig: Config> PendingSession<GGRSConfig> {
+    pub fn new(
+        socket: Box<dyn PacketSocket<GGRSConfig::Address>>,
+        players: Vec<PlayerType<GGRSConfig::Address>>,
+        tuning_hash: u64,
+        local_profile: Profile,
+    ) -> Self {
+        let handshake =
+            Handshake::local(players.len(), tuning_hash, rand::random(), &local_profile);
+        Self {
+            socket: GameSocket::new(socket, handshake),
+            players,
+            local_profile,
+            received: default(),
+            acknowledged: default(),
+        }
+    }
+
+    fn remote_addresses(&self) -> impl Iterator<Item = &GGRSConfig::Address> {
+        self.players.iter().filter_map(|player| match player {
+            PlayerType::Remote(addr) => Some(addr),
+            _ => None,
+        })
+    }
+}
+
+/// Exchanges handshakes with all remote peers and starts the GGRS session once everyone agrees.
+/// On a mismatch we report an error instead, as the session would desync anyway.
+pub fn exchange_handshakes<GGRSConfig: Config>(
+    mut commands: Commands,
+    mut pending_session: ResMut<Option<PendingSession<GGRSConfig>>>,
+    // `State` alone refers to the GGRS state defined above
+    mut state: ResMut<bevy::prelude::State<GameState>>,
+    mut errors: EventWriter<NetworkingError>,
+) {
+    let pending = match pending_session.as_mut() {
+        Some(pending) => pending,
+        // Either there are not enough players yet or the session has already started
+        None => return,
+    };
+
+    for (addr, handshake) in pending.socket.receive_handshakes() {
+        // anyone can send packets to our port, but only our peers can make the session fail
+        if !pending.remote_addresses().any(|remote| *remote == addr) {
+            log::warn!("Ignoring handshake from {:?}, which is not a peer", addr);
+            continue;
+        }
+        let verified = handshake.and_then(|handshake| {
+            pending.socket.handshake().verify(&handshake)?;
+            Ok(handshake)
+        });
+        let handshake = match verified {
+            Ok(handshake) => handshake,
+            Err(error) => {
+                *pending_session = None;
+                errors.send(error.into());
+                return;
+            }
+        };
+        if handshake.is_acknowledged() {
+            pending.acknowledged.insert(addr.clone());
+        }
+        pending.received.insert(addr, handshake);
+    }
+
+    let remotes: Vec<_> = pending.remote_addresses().cloned().collect();
+    for addr in &remotes {
+        let handshake = pending
+            .socket
+            .handshake()
+            .acknowledging(pending.received.contains_key(addr));
+        pending.socket.send_handshake(handshake, addr);
+    }
+
+    if !remotes
+        .iter()
+        .all(|addr| pending.acknowledged.contains(addr))
+    {
+        return;
+    }
+    log::info!("All peers agreed on the handshake, starting session");
+
+    // consume the socket (currently required because GGRS takes ownership of its socket)
+    let PendingSession {
+        socket,
+        players,
+        local_profile,
+        received,
+        ..
+    } = pending_session.take().unwrap();
+
+    let profiles = players
+        .iter()
+        .map(|player_type| match player_type {
+            PlayerType::Remote(addr) => received
+                .get(addr)
+                .map(Handshake::profile)
+                .unwrap_or_default(),
+            _ => local_profile.clone(),
+        })
+        .collect();
+    // every peer combines the same seeds, so everyone ends up with the same one
+    let seed = received
+        .values()
+        .fold(socket.handshake().seed, |seed, handshake| {
+            seed ^ handshake.seed
+        });
+
+    // handed to GGRS instead of the real session while we skip frames, see `crate::networking::sync`
+    let idle_session = players.iter().find_map(|player_type| match player_type {
+        PlayerType::Remote(addr) => Some(
+            SessionBuilder::<GGRSConfig>::new()
+                .with_num_players(players.len())
+                .start_spectator_session(addr.clone(), IdleSocket),
+        ),
+        _ => None,
+    });
+
+    let checksum_peers = players
+        .iter()
+        .enumerate()
+        .filter_map(|(handle, player_type)| match player_type {
+            PlayerType::Remote(addr) => Some((addr.clone(), handle)),
+            _ => None,
+        })
+        .collect();
+    let checksums = ChecksumMailbox::default();
+    let socket = socket.with_checksums(checksum_peers, checksums.clone());
+
+    let mut handles = Vec::new();
+    let session =
+        create_session_builder::<GGRSConfig>(players.len()).and_then(|mut p2p_session| {
+            for (i, player_type) in players.into_iter().enumerate() {
+                if player_type == PlayerType::Local {
+                    handles.push(i);
+                }
+                p2p_session = p2p_session.add_player(player_type, i)?;
+            }
+            // start the GGRS session
+            p2p_session
+                .start_p2p_session(socket)
+                .map_err(NetworkingError::from)
+        });
+
+    match session {
+        Ok(session) => {
+            commands.insert_resource(session);
+            if let Some(idle_session) = idle_session {
+                commands.insert_resource(idle_session);
+            }
+            commands.insert_resource(LocalHandles { handles });
+            commands.insert_resource(PlayerProfiles(profiles));
+            commands.insert_resource(MatchSeed(seed));
+            commands.insert_resource(checksums);
+            commands.insert_resource(SessionType::P2PSession);
+            state.set(GameState::Playing).unwrap();
+        }
+        Err(error) => errors.send(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::handshake::HandshakeError;
+    use crate::networking::headless::HeadlessConfig;
+    use crate::networking::loopback::{LoopbackNetwork, LoopbackSocket};
+    use crate::networking::protocol::PROTOCOL_VERSION;
+    use crate::networking::socket::Channel;
+    use bevy::ecs::event::Events;
+
+    /// Peer 0 on the network, waiting for the handshake of peer 1
+    fn connecting_app(network: &LoopbackNetwork<usize>) -> App {
+        let pending = PendingSession::<HeadlessConfig>::new(
+            Box::new(network.socket(0)),
+            vec![PlayerType::Local, PlayerType::Remote(1)],
+            0,
+            Profile::default(),
+        );
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_state(GameState::Connecting)
+            .add_event::<NetworkingError>()
+            .insert_resource(Some(pending))
+            .add_system(exchange_handshakes::<HeadlessConfig>);
+        app
+    }
+
+    /// Sends our handshake from peer 1, after letting `change` modify its header and payload
+    fn send_handshake(socket: &mut LoopbackSocket<usize>, change: impl FnOnce(&mut Vec<u8>)) {
+        let handshake = Handshake::local(2, 0, 0, &Profile::default());
+        let mut bytes = handshake.to_bytes();
+        change(&mut bytes);
+        let mut packet = vec![Channel::Handshake as u8];
+        packet.extend(bytes);
+        socket.send_packet(&packet, &0);
+    }
+
+    fn handshake_errors(app: &mut App) -> Vec<HandshakeError> {
+        app.update();
+        assert!(
+            app.world
+                .resource::<Option<PendingSession<HeadlessConfig>>>()
+                .is_none(),
+            "Session is still waiting for the peer"
+        );
+        let events = app.world.resource::<Events<NetworkingError>>();
+        events
+            .get_reader()
+            .iter(events)
+            .filter_map(|error| match error {
+                NetworkingError::Handshake(error) => Some(error.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn handshakes_from_others_than_our_peers_are_ignored() {
+        let network = LoopbackNetwork::default();
+        let mut app = connecting_app(&network);
+        let mut stranger = network.socket(2);
+        stranger.send_packet(&[Channel::Handshake as u8, 0xff], &0);
+        send_handshake(&mut stranger, |bytes| bytes.truncate(2));
+        app.update();
+
+        assert!(app
+            .world
+            .resource::<Option<PendingSession<HeadlessConfig>>>()
+            .is_some());
+        let events = app.world.resource::<Events<NetworkingError>>();
+        assert_eq!(events.get_reader().iter(events).count(), 0);
+    }
+
+    #[test]
+    fn handshakes_with_another_layout_are_an_error() {
+        let network = LoopbackNetwork::default();
+        let mut app = connecting_app(&network);
+        // a peer that added a field to the handshake without bumping the protocol version
+        send_handshake(&mut network.socket(1), |bytes| {
+            let length = u16::from_ne_bytes([bytes[2], bytes[3]]) + 8;
+            bytes[2..4].copy_from_slice(&length.to_ne_bytes());
+            bytes.extend_from_slice(&[0; 8]);
+        });
+
+        assert!(matches!(
+            handshake_errors(&mut app)[..],
+            [HandshakeError::Layout { .. }]
+        ));
+    }
+
+    #[test]
+    fn handshakes_of_other_protocol_versions_are_an_error() {
+        let network = LoopbackNetwork::default();
+        let mut app = connecting_app(&network);
+        // an older peer, whose handshake was missing a field
+        send_handshake(&mut network.socket(1), |bytes| {
+            bytes[..2].copy_from_slice(&(PROTOCOL_VERSION - 1).to_ne_bytes());
+            bytes.truncate(bytes.len() - 8);
+        });
+
+        assert_eq!(
+            handshake_errors(&mut app),
+            [HandshakeError::ProtocolVersion {
+                local: PROTOCOL_VERSION,
+                remote: PROTOCOL_VERSION - 1
+            }]
+        );
+    }
 }
@@ -1,9 +1,14 @@
-use super::shared::{self, create_session_builder};
-use crate::{networking::protocol::LocalHandles, GameState};
+use super::shared::{self, exchange_handshakes, PendingSession};
+use crate::{
+    config::NUM_PLAYERS,
+    networking::{protocol::PROTOCOL_VERSION, socket::PacketSocket},
+    profile::Profile,
+    tuning::Tuning,
+    GameState,
+};
 use bevy::{log, prelude::*, tasks::IoTaskPool};
-use bevy_ggrs::SessionType;
 use bevy_web_resizer::Plugin as WebResizerPlugin;
-use ggrs::{Config, PlayerType, SessionBuilder};
+use ggrs::Config;
 use matchbox_socket::WebRtcSocket;
 
 #[derive(Debug, Default)]
@@ -12,10 +17,18 @@ impl Plugin for WasmPlugin {
     fn build(&self, app: &mut App) {
         log::info!("Using wasm networking plugin");
 
-        app.add_system_set(
-            SystemSet::on_enter(GameState::Playing).with_system(start_matchbox_socket),
-        )
-        .add_system_set(SystemSet::on_update(GameState::Playing).with_system(wait_for_players));
+        app.init_resource::<Option<PendingSession<WasmConfig>>>()
+            .add_system_set(
+                SystemSet::on_enter(GameState::Connecting).with_system(start_matchbox_socket),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Connecting)
+                    .with_system(wait_for_players)
+                    .with_system(exchange_handshakes::<WasmConfig>),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Connecting).with_system(close_matchbox_socket),
+            );
 
         app.add_plugin(WebResizerPlugin);
     }
@@ -31,8 +44,25 @@ impl Config for WasmConfig {
     type Address = String;
 }
 
+impl PacketSocket<String> for WebRtcSocket {
+    fn send_packet(&mut self, packet: &[u8], addr: &String) {
+        self.send(packet.into(), addr.clone());
+    }
+
+    fn receive_packets(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.receive()
+            .into_iter()
+            .map(|(peer, packet)| (peer, packet.into_vec()))
+            .collect()
+    }
+}
+
 fn start_matchbox_socket(mut commands: Commands, task_pool: Res<IoTaskPool>) {
-    let room_url = "wss://matchbox.hohenheim.ch/extreme-bevy/next_2";
+    // Clients with different protocol versions are put into different rooms and will never be matched
+    let room_url = format!(
+        "wss://matchbox.hohenheim.ch/extreme-bevy/v{}_next_2",
+        PROTOCOL_VERSION
+    );
     log::info!("Connecting to matchbox server: {}", room_url);
     let (socket, message_loop) = WebRtcSocket::new(room_url);
 
@@ -42,44 +72,38 @@ fn start_matchbox_socket(mut commands: Commands, task_pool: Res<IoTaskPool>) {
     commands.insert_resource(Some(socket));
 }
 
-fn wait_for_players(mut commands: Commands, mut socket: ResMut<Option<WebRtcSocket>>) {
+fn wait_for_players(
+    mut socket: ResMut<Option<WebRtcSocket>>,
+    mut pending_session: ResMut<Option<PendingSession<WasmConfig>>>,
+    tuning: Res<Tuning>,
+    profile: Res<Profile>,
+) {
     let socket = socket.as_mut();
     if socket.is_none() {
-        // If there is no socket we've already started the game
+        // If there is no socket we've already found all players
         return;
     }
     // Check for new connections
     socket.as_mut().unwrap().accept_new_connections();
     let players = socket.as_ref().unwrap().players();
 
-    let num_players = 2;
-    if players.len() < num_players {
+    if players.len() < NUM_PLAYERS {
         return;
     }
 
-    log::info!("All players have joined, starting game");
+    log::info!("All players have joined, exchanging handshakes");
 
-    // consume the socket (currently required because GGRS takes ownership of its socket)
+    // consume the socket, it is passed on to GGRS once all handshakes are done
     let socket = socket.take().unwrap();
+    *pending_session = Some(PendingSession::new(
+        Box::new(socket),
+        players,
+        tuning.hash(),
+        profile.clone(),
+    ));
+}
 
-    // create a GGRS P2P session
-    let mut p2p_session: SessionBuilder<WasmConfig> = create_session_builder(num_players);
-
-    let mut handles = Vec::new();
-    for (i, player_type) in players.into_iter().enumerate() {
-        if player_type == PlayerType::Local {
-            handles.push(i);
-        }
-        p2p_session = p2p_session
-            .add_player(player_type, i)
-            .expect("Failed to add player");
-    }
-
-    // start the GGRS session
-    let session = p2p_session
-        .start_p2p_session(socket)
-        .expect("Session could not be created.");
-    commands.insert_resource(session);
-    commands.insert_resource(LocalHandles { handles });
-    commands.insert_resource(SessionType::P2PSession);
+/// Drops the socket if we stopped connecting before all players joined
+fn close_matchbox_socket(mut socket: ResMut<Option<WebRtcSocket>>) {
+    *socket = None;
 }
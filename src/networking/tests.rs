@@ -0,0 +1,280 @@
+//! Runs the rollback schedule headless with scripted inputs,
+//! once in a sync test session and once with two P2P sessions talking over an in-process loopback.
+
+use super::desync::DesyncDetector;
+use super::headless::{p2p_apps, run_until, run_until_frame, sync_test_app, InputScript};
+use super::protocol::{InputFlags, InputProtocol};
+use super::simulator::NetworkConditions;
+use super::sync::SyncStatus;
+use super::FrameCount;
+use crate::combat::{Health, Score};
+use crate::config::{FPS, INPUT_DELAY, MAX_PREDICTION_WINDOW};
+use crate::player::{spawn_position, Player};
+use crate::position::Position;
+use crate::rng::{GameRng, MatchSeed};
+use crate::tuning::Tuning;
+use crate::weapon::Weapon;
+use bevy::prelude::*;
+use ggrs::PlayerHandle;
+use std::time::{Duration, Instant};
+
+/// Real time after which a test gives up waiting for the simulation to reach a frame
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Frames rolled back by the sync tests every frame
+const CHECK_DISTANCE: usize = 2;
+
+fn run(apps: &mut [App], frame: u32) {
+    assert!(
+        run_until_frame(apps, frame, TIMEOUT),
+        "Simulation did not reach frame {} in time",
+        frame
+    );
+}
+
+fn player_positions(app: &mut App) -> Vec<Vec2> {
+    let mut players: Vec<_> = app
+        .world
+        .query::<(&Player, &Position)>()
+        .iter(&app.world)
+        .map(|(player, position)| (player.handle(), position.0))
+        .collect();
+    players.sort_by_key(|(handle, _)| *handle);
+    players.into_iter().map(|(_, position)| position).collect()
+}
+
+fn health(app: &mut App, handle: PlayerHandle) -> u32 {
+    app.world
+        .query::<(&Player, &Health)>()
+        .iter(&app.world)
+        .find(|(player, _)| player.handle() == handle)
+        .map(|(_, health)| health.current)
+        .unwrap()
+}
+
+fn player_component<T: Component + Copy>(app: &mut App, handle: PlayerHandle) -> T {
+    app.world
+        .query::<(&Player, &T)>()
+        .iter(&app.world)
+        .find(|(player, _)| player.handle() == handle)
+        .map(|(_, component)| *component)
+        .unwrap()
+}
+
+/// Player 0 walks right for `MOVED_FRAMES` frames, player 1 stands still
+fn walk_right_script() -> InputScript {
+    InputScript::default().with(0, MOVED_FRAMES, InputFlags::RIGHT.into())
+}
+
+const MOVED_FRAMES: usize = 30;
+
+fn assert_walked_right(positions: &[Vec2]) {
+    let distance = MOVED_FRAMES as f32 * Tuning::default().player_speed / FPS as f32;
+    let expected = spawn_position(0) + Vec2::new(distance, 0.);
+    assert!(
+        positions[0].distance(expected) < 1e-4,
+        "Player 0 is at {}, expected {}",
+        positions[0],
+        expected
+    );
+    assert_eq!(positions[1], spawn_position(1));
+}
+
+#[test]
+fn players_move_in_sync_test() {
+    let mut apps = [sync_test_app(walk_right_script(), CHECK_DISTANCE)];
+    run(&mut apps, MOVED_FRAMES as u32 + 10);
+    assert_walked_right(&player_positions(&mut apps[0]));
+}
+
+#[test]
+fn players_move_in_p2p_session() {
+    let mut apps = p2p_apps(walk_right_script(), NetworkConditions::default());
+    // the inputs are delayed and have to reach the other peer before predictions are corrected
+    run(
+        &mut apps,
+        (MOVED_FRAMES + INPUT_DELAY + MAX_PREDICTION_WINDOW) as u32,
+    );
+
+    let positions: Vec<_> = apps.iter_mut().map(player_positions).collect();
+    for peer_positions in &positions {
+        assert_walked_right(peer_positions);
+    }
+    assert!(apps.iter().all(|app| app
+        .world
+        .resource::<DesyncDetector>()
+        .desync_frame
+        .is_none()));
+}
+
+#[test]
+fn recommended_frames_are_skipped_in_p2p_session() {
+    let mut apps = p2p_apps(walk_right_script(), NetworkConditions::default());
+    run(&mut apps, 10);
+    let frame_duration = Duration::from_secs_f64(1. / FPS as f64);
+    let skipped_frames = FPS as u32 / 2;
+    let start = Instant::now();
+    apps[0]
+        .world
+        .resource_mut::<SyncStatus>()
+        .recommend_wait(skipped_frames, start);
+    let frame = apps[0].world.resource::<FrameCount>().frame;
+
+    run_until(&mut apps, start + frame_duration * (skipped_frames - 2));
+    assert_eq!(apps[0].world.resource::<FrameCount>().frame, frame);
+    // GGRS doesn't catch up on the skipped frames afterwards
+    run_until(&mut apps, start + frame_duration * (skipped_frames + 6));
+    assert!(apps[0].world.resource::<FrameCount>().frame < frame + skipped_frames / 2);
+
+    run(
+        &mut apps,
+        frame + (MOVED_FRAMES + INPUT_DELAY + MAX_PREDICTION_WINDOW) as u32,
+    );
+    for app in &mut apps {
+        assert_walked_right(&player_positions(app));
+        assert!(app
+            .world
+            .resource::<DesyncDetector>()
+            .desync_frame
+            .is_none());
+    }
+}
+
+#[test]
+fn diverged_peers_are_detected_in_p2p_session() {
+    let mut apps = p2p_apps(InputScript::default(), NetworkConditions::default());
+    run(&mut apps, 10);
+    // a change outside of the simulation, which the other peer never learns about
+    let world = &mut apps[1].world;
+    for mut health in world.query::<&mut Health>().iter_mut(world) {
+        health.current -= 1;
+    }
+    // the checksums are only sent once the frames can't be rolled back anymore
+    run(&mut apps, (10 + MAX_PREDICTION_WINDOW + FPS) as u32);
+
+    assert!(apps.iter().all(|app| app
+        .world
+        .resource::<DesyncDetector>()
+        .desync_frame
+        .is_some()));
+}
+
+#[test]
+fn bullets_hit_players_in_sync_test() {
+    // player 1 shoots once towards player 0
+    let script = InputScript::default().with(
+        1,
+        1,
+        InputProtocol::with_aim(InputFlags::FIRE, Vec2::new(-1., 0.)),
+    );
+    let mut apps = [sync_test_app(script, CHECK_DISTANCE)];
+    run(&mut apps, FPS as u32);
+
+    let max_health = Tuning::default().max_health;
+    assert_eq!(health(&mut apps[0], 0), max_health - 1);
+    assert_eq!(health(&mut apps[0], 1), max_health);
+}
+
+#[test]
+fn picked_up_weapons_are_used_in_sync_test() {
+    let tuning = Tuning::default();
+    // the pickup in the center, between both players
+    let pickup = &tuning.pickups[2];
+    assert_eq!(pickup.position, (0., 0.));
+    let definition = tuning.weapon(pickup.weapons[0]);
+
+    // player 0 walks onto the pickup and shoots player 1 once
+    let walk_frames = (2. / (tuning.player_speed / FPS as f32)).ceil() as usize;
+    let script = InputScript::default()
+        .with(0, walk_frames, InputFlags::RIGHT.into())
+        .with(
+            0,
+            1,
+            InputProtocol::with_aim(InputFlags::FIRE, Vec2::new(1., 0.)),
+        );
+    let mut apps = [sync_test_app(script, CHECK_DISTANCE)];
+    run(&mut apps, walk_frames as u32 + FPS as u32);
+
+    let weapon: Weapon = player_component(&mut apps[0], 0);
+    assert_eq!(weapon.index, pickup.weapons[0]);
+    assert_eq!(Some(weapon.ammo + 1), definition.ammo);
+    // it's the railgun, which kills with a single hit
+    assert!(definition.damage >= tuning.max_health);
+    let score: Score = player_component(&mut apps[0], 1);
+    assert_eq!(score.deaths, 1);
+}
+
+#[test]
+fn dashing_players_pass_through_bullets_in_sync_test() {
+    let tuning = Tuning::default();
+    // player 0 dashes through a bullet player 1 shoots at them
+    let script = InputScript::default()
+        .with(0, 1, (InputFlags::RIGHT | InputFlags::DASH).into())
+        .with(
+            1,
+            1,
+            InputProtocol::with_aim(InputFlags::FIRE, Vec2::new(-1., 0.)),
+        );
+    let mut apps = [sync_test_app(script, CHECK_DISTANCE)];
+    run(&mut apps, FPS as u32);
+
+    let distance = tuning.dash_duration as f32 * tuning.dash_speed / FPS as f32;
+    let expected = spawn_position(0) + Vec2::new(distance, 0.);
+    let position = player_positions(&mut apps[0])[0];
+    assert!(
+        position.distance(expected) < 1e-4,
+        "Player 0 is at {}, expected {}",
+        position,
+        expected
+    );
+    assert_eq!(health(&mut apps[0], 0), tuning.max_health);
+}
+
+#[test]
+fn peers_with_the_same_seed_agree_on_spread_and_respawns_in_p2p_session() {
+    let tuning = Tuning::default();
+    let steps = |distance: f32| (distance / (tuning.player_speed / FPS as f32)).ceil() as usize;
+    // player 0 fires the shotgun from above the center at player 1,
+    // then picks up the railgun in the center and fires again, so player 1 dies at least once
+    let script = InputScript::default()
+        .with(0, steps(3.), InputFlags::UP.into())
+        .with(0, steps(2.), InputFlags::RIGHT.into())
+        .with(
+            0,
+            1,
+            InputProtocol::with_aim(InputFlags::FIRE, Vec2::new(2., -3.)),
+        )
+        .with(0, steps(3.), InputFlags::DOWN.into())
+        .with(0, FPS, InputFlags::empty().into())
+        .with(
+            0,
+            1,
+            InputProtocol::with_aim(InputFlags::FIRE, Vec2::new(1., 0.)),
+        );
+    let mut apps = p2p_apps(script, NetworkConditions::default());
+    // like `reset_rng` does when a match starts
+    for app in &mut apps {
+        app.insert_resource(GameRng::new(MatchSeed(0x5eed)));
+    }
+    // enough for the scripts to run out and everything to be confirmed
+    run(&mut apps, 3 * FPS as u32);
+
+    let score: Score = player_component(&mut apps[0], 1);
+    assert!(score.deaths >= 1);
+    let position = player_positions(&mut apps[0])[1];
+    assert!(tuning
+        .respawn_points
+        .iter()
+        .any(|(x, y)| position == Vec2::new(*x, *y)));
+    // how many pellets of the shotgun hit depends on the spread
+    let health_left = health(&mut apps[0], 1);
+    for app in &mut apps[1..] {
+        assert_eq!(player_positions(app)[1], position);
+        assert_eq!(player_component::<Score>(app, 1), score);
+        assert_eq!(health(app, 1), health_left);
+    }
+    let rng = *apps[0].world.resource::<GameRng>();
+    assert!(apps
+        .iter()
+        .all(|app| *app.world.resource::<GameRng>() == rng));
+}
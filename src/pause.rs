@@ -0,0 +1,209 @@
+use crate::config::NUM_PLAYERS;
+use crate::kill_cam::KillCam;
+use crate::loading::FontAssets;
+use crate::menu::ButtonColors;
+use crate::networking::protocol::LocalHandles;
+use crate::settings::{close_settings_menu, open_settings_menu, Settings, SettingsMenu};
+use crate::GameState;
+use bevy::ecs::schedule::ShouldRun;
+use bevy::prelude::*;
+
+pub struct PausePlugin;
+
+/// This plugin is responsible for the in-game menu opened with escape.
+/// Online, the match keeps running in the background while the menu is open, since our peers can't wait for us.
+/// In local matches the simulation is frozen instead.
+impl Plugin for PausePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Pause>()
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(toggle_pause_menu)
+                    .with_system(click_pause_menu_buttons),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(close_pause_menu));
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Pause {
+    /// Whether the pause menu is shown. Local input is ignored while it is.
+    pub menu_open: bool,
+    /// Whether the rollback schedule is frozen. Only ever true in local matches, as peers would desync otherwise.
+    pub simulation_frozen: bool,
+}
+
+#[derive(Component)]
+struct PauseMenu;
+
+#[derive(Component, Clone, Copy)]
+enum PauseMenuButton {
+    Resume,
+    Settings,
+    LeaveMatch,
+}
+
+/// Run criteria for the gameplay systems in the rollback schedule
+pub fn run_simulation(
+    state: Res<State<GameState>>,
+    pause: Res<Pause>,
+    kill_cam: Option<Res<KillCam>>,
+) -> ShouldRun {
+    let kill_cam_frozen = kill_cam.map_or(false, |kill_cam| kill_cam.freezes_simulation);
+    if *state.current() == GameState::Playing && !pause.simulation_frozen && !kill_cam_frozen {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn toggle_pause_menu(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut pause: ResMut<Pause>,
+    local_handles: Res<LocalHandles>,
+    font_assets: Res<FontAssets>,
+    button_colors: Res<ButtonColors>,
+    settings: Res<Settings>,
+    menu_query: Query<Entity, With<PauseMenu>>,
+    settings_query: Query<Entity, With<SettingsMenu>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::Escape) {
+        return;
+    }
+    if pause.menu_open {
+        resume(&mut commands, &mut pause, &menu_query);
+        close_settings_menu(&mut commands, &settings, &settings_query);
+        return;
+    }
+
+    pause.menu_open = true;
+    pause.simulation_frozen = local_handles.handles.len() == NUM_PLAYERS;
+    spawn_pause_menu(&mut commands, &font_assets, &button_colors);
+}
+
+fn spawn_pause_root<'w, 's, 'a>(
+    commands: &'a mut Commands<'w, 's>,
+    name: &str,
+) -> bevy::ecs::system::EntityCommands<'w, 's, 'a> {
+    let mut root = commands.spawn_bundle(NodeBundle {
+        style: Style {
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            position_type: PositionType::Absolute,
+            flex_direction: FlexDirection::ColumnReverse,
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..Default::default()
+        },
+        color: Color::rgba(0.0, 0.0, 0.0, 0.6).into(),
+        ..Default::default()
+    });
+    root.insert(PauseMenu).insert(Name::new(name.to_owned()));
+    root
+}
+
+fn spawn_pause_menu(
+    commands: &mut Commands,
+    font_assets: &FontAssets,
+    button_colors: &ButtonColors,
+) {
+    spawn_pause_root(commands, "Pause Menu").with_children(|parent| {
+        for (button, label) in [
+            (PauseMenuButton::Resume, "Resume"),
+            (PauseMenuButton::Settings, "Settings"),
+            (PauseMenuButton::LeaveMatch, "Leave match"),
+        ] {
+            spawn_button(parent, font_assets, button_colors, button, label, 220.0);
+        }
+    });
+}
+
+fn spawn_button(
+    parent: &mut ChildBuilder,
+    font_assets: &FontAssets,
+    button_colors: &ButtonColors,
+    button: PauseMenuButton,
+    label: &str,
+    width: f32,
+) {
+    parent
+        .spawn_bundle(ButtonBundle {
+            style: Style {
+                size: Size::new(Val::Px(width), Val::Px(50.0)),
+                margin: Rect::all(Val::Px(10.0)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: button_colors.normal,
+            ..Default::default()
+        })
+        .insert(button)
+        .insert(Name::new(format!("{} Button", label)))
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    label,
+                    TextStyle {
+                        font: font_assets.fira_sans.clone(),
+                        font_size: 40.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                    Default::default(),
+                ),
+                ..Default::default()
+            });
+        });
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+fn click_pause_menu_buttons(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    button_colors: Res<ButtonColors>,
+    settings: Res<Settings>,
+    mut state: ResMut<State<GameState>>,
+    mut pause: ResMut<Pause>,
+    menu_query: Query<Entity, With<PauseMenu>>,
+    mut interaction_query: Query<
+        (&PauseMenuButton, &Interaction, &mut UiColor),
+        Changed<Interaction>,
+    >,
+) {
+    for (button, interaction, mut color) in interaction_query.iter_mut() {
+        match *interaction {
+            Interaction::Clicked => match button {
+                PauseMenuButton::Resume => resume(&mut commands, &mut pause, &menu_query),
+                PauseMenuButton::Settings => {
+                    open_settings_menu(&mut commands, &font_assets, &button_colors, &settings)
+                }
+                PauseMenuButton::LeaveMatch => state.set(GameState::Menu).unwrap(),
+            },
+            Interaction::Hovered => {
+                *color = button_colors.hovered;
+            }
+            Interaction::None => {
+                *color = button_colors.normal;
+            }
+        }
+    }
+}
+
+fn resume(commands: &mut Commands, pause: &mut Pause, menu_query: &Query<Entity, With<PauseMenu>>) {
+    *pause = Pause::default();
+    for menu in menu_query.iter() {
+        commands.entity(menu).despawn_recursive();
+    }
+}
+
+fn close_pause_menu(
+    mut commands: Commands,
+    mut pause: ResMut<Pause>,
+    settings: Res<Settings>,
+    menu_query: Query<Entity, With<PauseMenu>>,
+    settings_query: Query<Entity, With<SettingsMenu>>,
+) {
+    resume(&mut commands, &mut pause, &menu_query);
+    close_settings_menu(&mut commands, &settings, &settings_query);
+}
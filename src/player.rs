@@ -1,8 +1,18 @@
 use crate::actions::Actions;
-use crate::config::FPS;
+use crate::animation::AnimationState;
+use crate::combat::{FireCooldown, Health, Score};
+use crate::config::{FPS, NUM_PLAYERS};
+use crate::dash::Dash;
 use crate::loading::{SpriteAssets, TextureAssets};
+use crate::position::{CorrectionOffset, Position};
+use crate::profile::PlayerProfiles;
+use crate::rng::GameRng;
+use crate::tuning::Tuning;
+use crate::weapon::Weapon;
 use crate::GameState;
 use bevy::prelude::*;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use rand::seq::SliceRandom;
 
 pub struct PlayerPlugin;
 
@@ -15,8 +25,16 @@ impl Player {
     pub fn new(handle: usize) -> Self {
         Self { handle }
     }
+
+    pub fn handle(&self) -> usize {
+        self.handle
+    }
 }
 
+/// Marker for the camera showing the game world, as opposed to the UI camera
+#[derive(Component)]
+pub struct MainCamera;
+
 /// This plugin handles player related stuff like movement
 /// Player logic is only active during the State `GameState::Playing`
 impl Plugin for PlayerPlugin {
@@ -25,7 +43,8 @@ impl Plugin for PlayerPlugin {
             SystemSet::on_enter(GameState::Playing)
                 .with_system(spawn_players)
                 .with_system(spawn_camera),
-        );
+        )
+        .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(despawn_world));
     }
 }
 
@@ -35,59 +54,120 @@ fn spawn_camera(mut commands: Commands) {
     camera_bundle.orthographic_projection.scale = 1. / 50.;
     commands
         .spawn_bundle(camera_bundle)
+        .insert(MainCamera)
         .insert(Name::new("2D Camera"));
 }
 
-fn spawn_players(mut commands: Commands, textures: Res<TextureAssets>, sprites: Res<SpriteAssets>) {
-    spawn_player(
-        &mut commands,
-        &textures,
-        &sprites.bevy_one,
-        Vec3::new(-2.0, 0.0, 0.0),
-        Player::new(0),
-    );
-    spawn_player(
-        &mut commands,
-        &textures,
-        &sprites.bevy_two,
-        Vec3::new(2.0, 0.0, 0.0),
-        Player::new(1),
-    );
+fn despawn_world(
+    mut commands: Commands,
+    query: Query<Entity, Or<(With<Player>, With<MainCamera>)>>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/// Where a player starts the match and respawns after dying
+pub fn spawn_position(handle: usize) -> Vec2 {
+    match handle {
+        0 => Vec2::new(-2.0, 0.0),
+        _ => Vec2::new(2.0, 0.0),
+    }
+}
+
+/// Where a player respawns after dying: a random one of the [`Tuning::respawn_points`]
+pub fn respawn_position(handle: usize, tuning: &Tuning, rng: &mut GameRng) -> Vec2 {
+    match tuning.respawn_points.choose(rng) {
+        Some((x, y)) => Vec2::new(*x, *y),
+        None => spawn_position(handle),
+    }
+}
+
+fn spawn_players(
+    mut commands: Commands,
+    mut rollback_id_provider: ResMut<RollbackIdProvider>,
+    textures: Res<TextureAssets>,
+    sprites: Res<SpriteAssets>,
+    profiles: Option<Res<PlayerProfiles>>,
+    tuning: Res<Tuning>,
+) {
+    for handle in 0..NUM_PLAYERS {
+        let mut sprite = sprites.player.clone();
+        sprite.color = tuning.player_color(handle);
+        let mut name = format!("Player {}", handle);
+        if let Some(profiles) = &profiles {
+            let profile = profiles.get(handle);
+            sprite.color = profile.color.color();
+            name = profile.name;
+        }
+        spawn_player(
+            &mut commands,
+            &mut rollback_id_provider,
+            &textures,
+            sprite,
+            name,
+            Player::new(handle),
+            &tuning,
+        );
+    }
 }
 
 fn spawn_player(
     commands: &mut Commands,
+    rollback_id_provider: &mut RollbackIdProvider,
     textures: &Res<TextureAssets>,
-    sprite: &Sprite,
-    translation: Vec3,
+    sprite: TextureAtlasSprite,
+    name: String,
     player: Player,
+    tuning: &Tuning,
 ) {
     commands
-        .spawn_bundle(SpriteBundle {
-            texture: textures.texture_bevy.clone(),
-            transform: Transform::from_translation(translation),
-            sprite: sprite.clone(),
+        .spawn_bundle(SpriteSheetBundle {
+            texture_atlas: textures.player_sheet.clone(),
+            transform: Transform::from_translation(spawn_position(player.handle).extend(0.)),
+            sprite,
             ..default()
         })
-        .insert(Name::from(format!("Player {}", player.handle)))
-        .insert(player);
+        .insert(Name::from(name))
+        .insert_bundle(simulation_bundle(player, tuning))
+        .insert(CorrectionOffset::default())
+        .insert(Rollback::new(rollback_id_provider.next_id()));
+}
+
+/// Everything about a player that is simulated in the rollback schedule, without any rendering
+pub fn simulation_bundle(player: Player, tuning: &Tuning) -> impl Bundle {
+    (
+        player,
+        Position(spawn_position(player.handle)),
+        Health::full(tuning.max_health),
+        FireCooldown::default(),
+        Weapon::default(),
+        Dash::default(),
+        Score::default(),
+        AnimationState::default(),
+    )
 }
 
 pub fn move_players(
     actions: Res<Vec<Actions>>,
-    mut player_query: Query<(&mut Transform, &Player)>,
+    tuning: Res<Tuning>,
+    mut player_query: Query<(&mut Position, &Player, &Dash)>,
 ) {
-    for (mut transform, player) in player_query.iter_mut() {
+    for (mut position, player, dash) in player_query.iter_mut() {
+        if dash.is_dashing() {
+            position.0 += dash.direction * tuning.dash_speed / FPS as f32;
+            continue;
+        }
         let actions = &actions[player.handle];
-        move_player(actions, &mut transform);
+        move_player(actions, &tuning, &mut position);
     }
 }
 
-fn move_player(actions: &Actions, transform: &mut Transform) {
+fn move_player(actions: &Actions, tuning: &Tuning, position: &mut Position) {
     if actions.player_movement.is_none() {
         return;
     }
-    let speed = 15. / FPS as f32;
-    let movement = actions.player_movement.unwrap().extend(0.) * speed;
-    transform.translation += movement;
+    let speed = tuning.player_speed / FPS as f32;
+    let movement = actions.player_movement.unwrap() * speed;
+    position.0 += movement;
 }
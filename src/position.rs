@@ -0,0 +1,103 @@
+use crate::networking::RollbackStatus;
+use crate::settings::Settings;
+use crate::GameState;
+use bevy::prelude::*;
+
+pub struct PositionPlugin;
+
+/// Corrections larger than this are not smoothed, as the entity most likely teleported, e.g. by respawning
+const MAX_SMOOTHED_CORRECTION: f32 = 3.;
+
+/// This plugin moves the rendered [`Transform`] of simulated entities to their simulated [`Position`].
+/// When a rollback corrects a position, the entity does not teleport but glides to its new position,
+/// as configured in the [`Settings`].
+impl Plugin for PositionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(GameState::Playing)
+                .with_system(add_correction_offsets)
+                .with_system(smooth_transforms),
+        );
+    }
+}
+
+/// Position in the simulation. Unlike [`Transform`], which is only used for rendering, this is rolled back.
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq)]
+#[reflect(Component)]
+pub struct Position(pub Vec2);
+
+/// Difference between where an entity is rendered and where it is simulated. Decays over time.
+/// Not rolled back, as it describes what the player has already seen.
+#[derive(Component, Default, Debug)]
+pub struct CorrectionOffset {
+    offset: Vec2,
+    /// Position after the latest frame was simulated for the first time
+    predicted: Option<Vec2>,
+}
+
+/// Rollback system, runs before anything moves in a frame.
+/// If the previous frames were just resimulated, the difference to the previous prediction is added to the offset.
+pub fn apply_corrections(
+    status: Res<RollbackStatus>,
+    mut query: Query<(&Position, &mut CorrectionOffset)>,
+) {
+    if status.resimulating {
+        return;
+    }
+    for (position, mut correction) in query.iter_mut() {
+        if let Some(predicted) = correction.predicted {
+            let error = predicted - position.0;
+            correction.offset = if error.length() > MAX_SMOOTHED_CORRECTION {
+                Vec2::ZERO
+            } else {
+                correction.offset + error
+            };
+        }
+    }
+}
+
+/// Rollback system, runs after everything moved in a frame
+pub fn record_predictions(
+    status: Res<RollbackStatus>,
+    mut query: Query<(&Position, &mut CorrectionOffset)>,
+) {
+    if status.resimulating {
+        return;
+    }
+    for (position, mut correction) in query.iter_mut() {
+        correction.predicted = Some(position.0);
+    }
+}
+
+/// Entities spawned inside the rollback schedule, like bullets, don't know about rendering
+fn add_correction_offsets(
+    mut commands: Commands,
+    query: Query<(Entity, &Position), Without<CorrectionOffset>>,
+) {
+    for (entity, position) in query.iter() {
+        commands.entity(entity).insert_bundle((
+            CorrectionOffset::default(),
+            Transform::from_translation(position.0.extend(0.)),
+            GlobalTransform::default(),
+        ));
+    }
+}
+
+fn smooth_transforms(
+    time: Res<Time>,
+    settings: Res<Settings>,
+    mut query: Query<(&Position, &mut CorrectionOffset, &mut Transform)>,
+) {
+    let smoothing = settings.correction_smoothing.seconds();
+    let decay = if smoothing > 0. {
+        (-time.delta_seconds() / smoothing).exp()
+    } else {
+        0.
+    };
+    for (position, mut correction, mut transform) in query.iter_mut() {
+        correction.offset *= decay;
+        let rendered = position.0 + correction.offset;
+        transform.translation.x = rendered.x;
+        transform.translation.y = rendered.y;
+    }
+}
@@ -0,0 +1,294 @@
+use crate::history::HistoryMenu;
+use crate::loading::FontAssets;
+use crate::menu::ButtonColors;
+use crate::settings::SettingsMenu;
+use crate::storage;
+use crate::GameState;
+use bevy::{log, prelude::*};
+use serde::{Deserialize, Serialize};
+
+pub struct ProfilePlugin;
+
+const PROFILE_KEY: &str = "profile.ron";
+/// Names are sent to our peers in a fixed size buffer, so they are limited in bytes rather than characters
+pub const MAX_NAME_BYTES: usize = 16;
+
+/// This plugin owns the local player's [`Profile`] and lets the player edit it in the main menu.
+/// Once a session starts, the profiles of all players are available as [`PlayerProfiles`].
+impl Plugin for ProfilePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Profile::load())
+            .add_system_set(
+                SystemSet::on_update(GameState::Menu)
+                    .with_system(edit_profile_name)
+                    .with_system(click_color_button)
+                    .with_system(update_profile_editor),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Menu).with_system(save_profile))
+            .add_system_set(
+                SystemSet::on_exit(GameState::Playing).with_system(remove_player_profiles),
+            );
+    }
+}
+
+/// How the local player presents themselves to their opponents
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    pub color: PlayerColor,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: "Player".to_string(),
+            color: PlayerColor::Green,
+        }
+    }
+}
+
+impl Profile {
+    /// Builds a profile from untrusted input, e.g. a remote handshake or a hand edited file
+    pub fn new(name: &str, color: PlayerColor) -> Self {
+        let mut name: String = name.chars().filter(|c| !c.is_control()).collect();
+        while name.len() > MAX_NAME_BYTES {
+            name.pop();
+        }
+        let name = name.trim();
+        Self {
+            name: if name.is_empty() {
+                Profile::default().name
+            } else {
+                name.to_string()
+            },
+            color,
+        }
+    }
+
+    /// Loads the profile saved by [`Profile::save`], falling back to the default profile
+    pub fn load() -> Self {
+        let profile = match storage::read(PROFILE_KEY) {
+            Some(profile) => profile,
+            None => return default(),
+        };
+        match ron::from_str::<Profile>(&profile) {
+            Ok(profile) => Profile::new(&profile.name, profile.color),
+            Err(error) => {
+                log::warn!("Failed to read profile, using defaults: {}", error);
+                default()
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        let result = ron::ser::to_string_pretty(self, default())
+            .map_err(|error| error.to_string())
+            .and_then(|profile| storage::write(PROFILE_KEY, &profile));
+        if let Err(error) = result {
+            log::error!("Failed to save profile: {}", error);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlayerColor {
+    Green,
+    Red,
+    Blue,
+    Yellow,
+    Purple,
+    Orange,
+}
+
+impl PlayerColor {
+    pub const ALL: [PlayerColor; 6] = [
+        PlayerColor::Green,
+        PlayerColor::Red,
+        PlayerColor::Blue,
+        PlayerColor::Yellow,
+        PlayerColor::Purple,
+        PlayerColor::Orange,
+    ];
+
+    pub fn color(self) -> Color {
+        match self {
+            PlayerColor::Green => Color::rgb(0.0, 0.8, 0.0),
+            PlayerColor::Red => Color::rgb(0.8, 0.0, 0.0),
+            PlayerColor::Blue => Color::rgb(0.1, 0.3, 0.9),
+            PlayerColor::Yellow => Color::rgb(0.9, 0.8, 0.1),
+            PlayerColor::Purple => Color::rgb(0.6, 0.2, 0.8),
+            PlayerColor::Orange => Color::rgb(0.9, 0.5, 0.1),
+        }
+    }
+
+    /// Index into [`PlayerColor::ALL`], used to send the colour to our peers
+    pub fn index(self) -> u8 {
+        PlayerColor::ALL
+            .iter()
+            .position(|color| *color == self)
+            .unwrap() as u8
+    }
+
+    /// Unknown indices fall back to the first colour
+    pub fn from_index(index: u8) -> Self {
+        PlayerColor::ALL
+            .get(index as usize)
+            .copied()
+            .unwrap_or(PlayerColor::ALL[0])
+    }
+
+    fn next(self) -> Self {
+        PlayerColor::from_index((self.index() + 1) % PlayerColor::ALL.len() as u8)
+    }
+}
+
+/// Profiles of all players in the current session, indexed by player handle
+#[derive(Debug, Clone, Default)]
+pub struct PlayerProfiles(pub Vec<Profile>);
+
+impl PlayerProfiles {
+    /// Falls back to a generic profile, e.g. for sessions that were started without exchanging profiles
+    pub fn get(&self, handle: usize) -> Profile {
+        self.0.get(handle).cloned().unwrap_or_else(|| {
+            Profile::new(
+                &format!("Player {}", handle),
+                PlayerColor::from_index(handle as u8),
+            )
+        })
+    }
+}
+
+#[derive(Component)]
+struct NameInput;
+
+#[derive(Component)]
+struct ColorButton;
+
+/// Draws the name and colour of the local player, both can be changed in place
+pub fn spawn_profile_editor(
+    parent: &mut ChildBuilder,
+    font_assets: &FontAssets,
+    button_colors: &ButtonColors,
+    profile: &Profile,
+) {
+    let text_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 30.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    parent
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                align_items: AlignItems::Center,
+                margin: Rect::all(Val::Px(10.0)),
+                ..Default::default()
+            },
+            color: Color::NONE.into(),
+            ..Default::default()
+        })
+        .insert(Name::new("Profile"))
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(40.0), Val::Px(40.0)),
+                        margin: Rect::all(Val::Px(5.0)),
+                        ..Default::default()
+                    },
+                    color: profile.color.color().into(),
+                    ..Default::default()
+                })
+                .insert(ColorButton)
+                .insert(Name::new("Color Button"));
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(250.0), Val::Px(40.0)),
+                        margin: Rect::all(Val::Px(5.0)),
+                        padding: Rect::all(Val::Px(5.0)),
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    color: button_colors.normal,
+                    ..Default::default()
+                })
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(TextBundle {
+                            text: Text::with_section(
+                                format!("{}_", profile.name),
+                                text_style,
+                                Default::default(),
+                            ),
+                            ..Default::default()
+                        })
+                        .insert(NameInput)
+                        .insert(Name::new("Name Input"));
+                });
+        });
+}
+
+/// The main menu has no other text input, so everything typed goes into the name, unless an overlay is open
+fn edit_profile_name(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut characters: EventReader<ReceivedCharacter>,
+    mut profile: ResMut<Profile>,
+    overlay_query: Query<(), Or<(With<SettingsMenu>, With<HistoryMenu>)>>,
+) {
+    if !overlay_query.is_empty() {
+        // read anyway, so they don't end up in the name once the overlay is closed
+        characters.iter().for_each(drop);
+        return;
+    }
+    let mut name = profile.name.clone();
+    if keyboard_input.just_pressed(KeyCode::Back) {
+        name.pop();
+    }
+    for character in characters.iter() {
+        if !character.char.is_control() && name.len() + character.char.len_utf8() <= MAX_NAME_BYTES
+        {
+            name.push(character.char);
+        }
+    }
+    if name != profile.name {
+        // don't run the name through `Profile::new` yet, as that would prevent typing spaces
+        profile.name = name;
+    }
+}
+
+fn click_color_button(
+    mut profile: ResMut<Profile>,
+    interaction_query: Query<&Interaction, (Changed<Interaction>, With<ColorButton>)>,
+) {
+    for interaction in interaction_query.iter() {
+        if *interaction == Interaction::Clicked {
+            profile.color = profile.color.next();
+        }
+    }
+}
+
+fn update_profile_editor(
+    profile: Res<Profile>,
+    mut name_query: Query<&mut Text, With<NameInput>>,
+    mut color_query: Query<&mut UiColor, With<ColorButton>>,
+) {
+    if !profile.is_changed() {
+        return;
+    }
+    for mut text in name_query.iter_mut() {
+        text.sections[0].value = format!("{}_", profile.name);
+    }
+    for mut color in color_query.iter_mut() {
+        *color = profile.color.color().into();
+    }
+}
+
+fn save_profile(mut profile: ResMut<Profile>) {
+    *profile = Profile::new(&profile.name, profile.color);
+    profile.save();
+}
+
+fn remove_player_profiles(mut commands: Commands) {
+    commands.remove_resource::<PlayerProfiles>();
+}
@@ -0,0 +1,168 @@
+use crate::config::MAX_PREDICTION_WINDOW;
+use crate::networking::protocol::{InputFlags, InputProtocol, PROTOCOL_VERSION};
+use crate::networking::FrameCount;
+use crate::profile::Profile;
+use crate::rng::MatchSeed;
+use crate::storage;
+use crate::tuning::Tuning;
+use bevy::{log, prelude::*};
+use ggrs::InputStatus;
+use serde::{Deserialize, Serialize};
+
+pub mod snapshot;
+pub mod viewer;
+
+/// Everything needed to simulate a match again: the setup and the inputs of every player
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    /// Replays can only be played back with the [`InputProtocol`] they were recorded with
+    pub protocol_version: u16,
+    pub tuning: Tuning,
+    pub seed: u64,
+    pub profiles: Vec<Profile>,
+    /// The `input` and `aim` bytes of every player's [`InputProtocol`], for every frame starting at frame 1
+    pub inputs: Vec<Vec<(u8, u8)>>,
+}
+
+impl Replay {
+    pub fn load(key: &str) -> Option<Replay> {
+        let replay: Replay = match ron::from_str(&storage::read(key)?) {
+            Ok(replay) => replay,
+            Err(error) => {
+                log::warn!("Failed to read replay {}: {}", key, error);
+                return None;
+            }
+        };
+        if replay.protocol_version != PROTOCOL_VERSION {
+            log::warn!(
+                "Replay {} was recorded with protocol version {}, but we use {}",
+                key,
+                replay.protocol_version,
+                PROTOCOL_VERSION
+            );
+            return None;
+        }
+        Some(replay)
+    }
+
+    /// Inputs of every player at `frame`, which starts at 1
+    pub fn frame_inputs(&self, frame: u32) -> Option<Vec<InputProtocol>> {
+        let inputs = self.inputs.get(frame.checked_sub(1)? as usize)?;
+        Some(
+            inputs
+                .iter()
+                .map(|(input, aim)| InputProtocol {
+                    input: *input,
+                    aim: *aim,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.inputs.len() as u32
+    }
+}
+
+/// Inputs of the current match, see [`record_inputs`]. Not rolled back.
+#[derive(Default, Debug)]
+pub struct ReplayRecorder {
+    /// Inputs of every player for every simulated frame, starting at frame 1
+    inputs: Vec<Vec<InputProtocol>>,
+    /// Whether the latest simulation of a frame only used confirmed inputs
+    confirmed: Vec<bool>,
+}
+
+impl ReplayRecorder {
+    /// Number of frames from the start of the match whose inputs are final.
+    /// Frames older than the prediction window can't be rolled back anymore, so their inputs are final
+    /// even if they were predicted: GGRS only resimulates frames whose predictions turned out to be wrong.
+    pub fn confirmed_frames(&self) -> u32 {
+        let rollback_window = self.confirmed.len().saturating_sub(MAX_PREDICTION_WINDOW);
+        let confirmed = self.confirmed[rollback_window..]
+            .iter()
+            .position(|confirmed| !confirmed)
+            .map_or(self.confirmed.len(), |position| rollback_window + position);
+        confirmed as u32
+    }
+
+    /// Saves the confirmed part of the match and returns the key it was stored under
+    pub fn save(
+        &self,
+        key: &str,
+        tuning: &Tuning,
+        seed: MatchSeed,
+        profiles: Vec<Profile>,
+    ) -> Option<String> {
+        let frames = self.confirmed_frames() as usize;
+        if frames == 0 {
+            return None;
+        }
+        let replay = Replay {
+            protocol_version: PROTOCOL_VERSION,
+            tuning: tuning.clone(),
+            seed: seed.0,
+            profiles,
+            inputs: self.inputs[..frames]
+                .iter()
+                .map(|inputs| {
+                    inputs
+                        .iter()
+                        .map(|input| (input.input, input.aim))
+                        .collect()
+                })
+                .collect(),
+        };
+        let serialized = match ron::to_string(&replay) {
+            Ok(serialized) => serialized,
+            Err(error) => {
+                log::warn!("Failed to serialize replay: {}", error);
+                return None;
+            }
+        };
+        match storage::write(key, &serialized) {
+            Ok(()) => Some(key.to_string()),
+            Err(error) => {
+                log::warn!("Failed to save replay: {}", error);
+                None
+            }
+        }
+    }
+}
+
+pub fn reset_replay_recorder(mut recorder: ResMut<ReplayRecorder>) {
+    *recorder = default();
+}
+
+/// Rollback system remembering the inputs of every frame.
+/// Resimulated frames overwrite their inputs, so once a frame is confirmed, its latest inputs are the real ones.
+pub fn record_inputs(
+    frame_count: Res<FrameCount>,
+    inputs: Res<Vec<(InputProtocol, InputStatus)>>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    let index = match (frame_count.frame as usize).checked_sub(1) {
+        Some(index) => index,
+        None => return,
+    };
+    if recorder.inputs.len() <= index {
+        recorder.inputs.resize(index + 1, Vec::new());
+        recorder.confirmed.resize(index + 1, false);
+    }
+    recorder.inputs[index] = recorded_inputs(&inputs);
+    recorder.confirmed[index] = inputs
+        .iter()
+        .all(|(_, status)| *status != InputStatus::Predicted);
+}
+
+/// The inputs GGRS handed to the rollback schedule, as they are recorded to be played back later
+pub fn recorded_inputs(inputs: &[(InputProtocol, InputStatus)]) -> Vec<InputProtocol> {
+    inputs
+        .iter()
+        .map(|(input, status)| match status {
+            // disconnected players don't act, just like players without any input
+            InputStatus::Disconnected => InputProtocol::new(InputFlags::empty()),
+            _ => *input,
+        })
+        .collect()
+}
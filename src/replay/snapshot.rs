@@ -0,0 +1,111 @@
+use crate::networking::RegisterRollbackTypes;
+use bevy::{prelude::*, reflect::TypeRegistry, utils::HashMap};
+use bevy_ggrs::Rollback;
+use std::any::TypeId;
+
+/// Types that are saved in a [`WorldSnapshot`], the same ones GGRS saves and restores on rollbacks
+pub struct RollbackTypes(TypeRegistry);
+
+impl Default for RollbackTypes {
+    fn default() -> Self {
+        Self(TypeRegistry::default().register_rollback_types())
+    }
+}
+
+/// Copy of every rolled back component and resource, taken the way bevy_ggrs does it on every frame
+#[derive(Default)]
+pub struct WorldSnapshot {
+    /// Components of every entity with a [`Rollback`] component, by rollback id
+    entities: HashMap<u32, HashMap<TypeId, Box<dyn Reflect>>>,
+    resources: HashMap<TypeId, Box<dyn Reflect>>,
+}
+
+impl WorldSnapshot {
+    pub fn take(world: &mut World, types: &RollbackTypes) -> Self {
+        let mut snapshot = WorldSnapshot::default();
+        let entities: Vec<(Entity, u32)> = world
+            .query::<(Entity, &Rollback)>()
+            .iter(world)
+            .map(|(entity, rollback)| (entity, rollback.id()))
+            .collect();
+        for (entity, id) in entities {
+            let components = types
+                .0
+                .iter()
+                .filter_map(|registration| {
+                    let component = registration
+                        .data::<ReflectComponent>()?
+                        .reflect_component(world, entity)?;
+                    Some((registration.type_id(), component.clone_value()))
+                })
+                .collect();
+            snapshot.entities.insert(id, components);
+        }
+        snapshot.resources = types
+            .0
+            .iter()
+            .filter_map(|registration| {
+                let resource = registration
+                    .data::<ReflectResource>()?
+                    .reflect_resource(world)?;
+                Some((registration.type_id(), resource.clone_value()))
+            })
+            .collect();
+        snapshot
+    }
+
+    /// Brings every rolled back component and resource back to the state of the snapshot.
+    /// Entities spawned since are despawned and entities despawned since are spawned again.
+    pub fn restore(&self, world: &mut World, types: &RollbackTypes) {
+        let existing: HashMap<u32, Entity> = world
+            .query::<(Entity, &Rollback)>()
+            .iter(world)
+            .map(|(entity, rollback)| (rollback.id(), entity))
+            .collect();
+        for (id, entity) in &existing {
+            if !self.entities.contains_key(id) {
+                world.despawn(*entity);
+            }
+        }
+
+        for (id, components) in &self.entities {
+            let entity = match existing.get(id) {
+                Some(entity) => *entity,
+                None => world.spawn().insert(Rollback::new(*id)).id(),
+            };
+            for registration in types.0.iter() {
+                let reflect_component = match registration.data::<ReflectComponent>() {
+                    Some(reflect_component) => reflect_component,
+                    None => continue,
+                };
+                match components.get(&registration.type_id()) {
+                    Some(component) => {
+                        if reflect_component.reflect_component(world, entity).is_some() {
+                            reflect_component.apply_component(world, entity, &**component);
+                        } else {
+                            reflect_component.add_component(world, entity, &**component);
+                        }
+                    }
+                    None => reflect_component.remove_component(world, entity),
+                }
+            }
+        }
+
+        for registration in types.0.iter() {
+            let reflect_resource = match registration.data::<ReflectResource>() {
+                Some(reflect_resource) => reflect_resource,
+                None => continue,
+            };
+            match self.resources.get(&registration.type_id()) {
+                Some(resource) => {
+                    if reflect_resource.reflect_resource(world).is_some() {
+                        reflect_resource.apply_resource(world, &**resource);
+                    } else {
+                        reflect_resource.insert_resource(world, &**resource);
+                    }
+                }
+                None => reflect_resource.remove_resource(world),
+            }
+        }
+    }
+}
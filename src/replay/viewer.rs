@@ -0,0 +1,476 @@
+use crate::config::{FPS, NUM_PLAYERS};
+use crate::events::GameplayEvents;
+use crate::loading::FontAssets;
+use crate::networking::protocol::{InputProtocol, LocalHandles};
+use crate::networking::{rollback_schedule, FrameCount, RollbackStatus};
+use crate::pause::Pause;
+use crate::player::{MainCamera, Player};
+use crate::position::CorrectionOffset;
+use crate::profile::PlayerProfiles;
+use crate::replay::snapshot::{RollbackTypes, WorldSnapshot};
+use crate::replay::Replay;
+use crate::rng::MatchSeed;
+use crate::GameState;
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use ggrs::InputStatus;
+use std::collections::BTreeMap;
+
+/// Frames between two snapshots, which are taken the first time playback passes them
+const SNAPSHOT_INTERVAL: u32 = FPS as u32;
+/// Frames skipped with the arrow keys
+const SEEK_STEP: u32 = 5 * FPS as u32;
+/// Frames simulated at most per update, so seeking far ahead does not freeze the game
+const MAX_FRAMES_PER_UPDATE: u32 = 10 * FPS as u32;
+const SPEEDS: [f32; 7] = [0.125, 0.25, 0.5, 1., 2., 4., 8.];
+const NORMAL_SPEED: usize = 3;
+/// Units per second the free camera moves
+const CAMERA_SPEED: f32 = 10.;
+/// Zoom factor per step of the mouse wheel
+const ZOOM_STEP: f32 = 1.1;
+
+pub struct ReplayViewerPlugin;
+
+/// This plugin plays back a [`Replay`] that was chosen in the match history.
+/// The replay can be paused, stepped through frame by frame, slowed down, sped up and seeked on a timeline,
+/// while the camera follows any player or moves freely.
+impl Plugin for ReplayViewerPlugin {
+    fn build(&self, app: &mut App) {
+        // like the GGRS stage, the replay is simulated before the frame is updated
+        app.add_system_to_stage(CoreStage::PreUpdate, advance_replay.exclusive_system())
+            .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(spawn_replay_ui))
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(control_playback)
+                    .with_system(seek_on_timeline)
+                    .with_system(move_replay_camera)
+                    .with_system(update_replay_ui),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(end_replay));
+    }
+}
+
+/// A replay that is being watched. While it exists, the match in [`GameState::Playing`] is driven by the replay
+/// instead of a GGRS session: the playback runs its own copy of the rollback schedule with the recorded inputs.
+pub struct ReplayPlayback {
+    replay: Replay,
+    schedule: Schedule,
+    types: RollbackTypes,
+    /// Snapshots of the world by frame, seeking restores the latest one before the target and simulates from there
+    snapshots: BTreeMap<u32, WorldSnapshot>,
+    pub paused: bool,
+    speed: usize,
+    /// Frames to simulate, accumulated from the real time passed
+    accumulator: f32,
+    seek_target: Option<u32>,
+    /// Player the camera follows, the camera can be moved freely if there is none
+    pub follow: Option<usize>,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            schedule: rollback_schedule(),
+            types: default(),
+            snapshots: default(),
+            paused: false,
+            speed: NORMAL_SPEED,
+            accumulator: 0.,
+            seek_target: None,
+            follow: None,
+        }
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.replay.frames()
+    }
+
+    pub fn speed(&self) -> f32 {
+        SPEEDS[self.speed]
+    }
+
+    /// Jumps to `frame`, which is reached within the next updates
+    pub fn seek(&mut self, frame: u32) {
+        self.seek_target = Some(frame.min(self.frames()));
+    }
+
+    fn advance(&mut self, world: &mut World, delta_seconds: f32) {
+        if self.snapshots.is_empty() {
+            let frame = world.resource::<FrameCount>().frame;
+            self.snapshots
+                .insert(frame, WorldSnapshot::take(world, &self.types));
+        }
+
+        if let Some(target) = self.seek_target {
+            self.rewind(world, target);
+            let frame = world.resource::<FrameCount>().frame;
+            for _ in frame..target.min(frame + MAX_FRAMES_PER_UPDATE) {
+                if !self.simulate_frame(world) {
+                    break;
+                }
+            }
+            if world.resource::<FrameCount>().frame >= target || self.at_end(world) {
+                self.seek_target = None;
+            }
+            // whatever was presented before the seek is gone, and the frames skipped over are not presented
+            world.resource_mut::<GameplayEvents>().reset();
+            return;
+        }
+
+        if self.paused {
+            return;
+        }
+        self.accumulator = (self.accumulator + delta_seconds * self.speed() * FPS as f32)
+            .min(MAX_FRAMES_PER_UPDATE as f32);
+        while self.accumulator >= 1. {
+            self.accumulator -= 1.;
+            if !self.simulate_frame(world) {
+                // the end of the replay
+                self.paused = true;
+                self.accumulator = 0.;
+            }
+        }
+    }
+
+    fn at_end(&self, world: &World) -> bool {
+        world.resource::<FrameCount>().frame >= self.frames()
+    }
+
+    /// Restores the latest snapshot before `target`, unless simulating on from the current frame gets there sooner
+    fn rewind(&mut self, world: &mut World, target: u32) {
+        let frame = world.resource::<FrameCount>().frame;
+        let (snapshot_frame, snapshot) = match self.snapshots.range(..=target).next_back() {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        if frame <= target && *snapshot_frame <= frame {
+            return;
+        }
+        snapshot.restore(world, &self.types);
+
+        // the frames after the snapshot are simulated anew, not resimulated, so nothing is smoothed
+        *world.resource_mut::<RollbackStatus>() = RollbackStatus {
+            latest_frame: *snapshot_frame,
+            resimulating: false,
+        };
+        for mut correction in world.query::<&mut CorrectionOffset>().iter_mut(world) {
+            *correction = default();
+        }
+    }
+
+    /// Returns `false` at the end of the replay
+    fn simulate_frame(&mut self, world: &mut World) -> bool {
+        let frame = world.resource::<FrameCount>().frame + 1;
+        let inputs = match self.replay.frame_inputs(frame) {
+            Some(inputs) => inputs,
+            None => return false,
+        };
+        world.insert_resource::<Vec<(InputProtocol, InputStatus)>>(
+            inputs
+                .into_iter()
+                .map(|input| (input, InputStatus::Confirmed))
+                .collect(),
+        );
+        self.schedule.run(world);
+
+        if frame % SNAPSHOT_INTERVAL == 0 {
+            let types = &self.types;
+            self.snapshots
+                .entry(frame)
+                .or_insert_with(|| WorldSnapshot::take(world, types));
+        }
+        true
+    }
+}
+
+/// Starts watching `replay` once the state is set to [`GameState::Playing`]
+pub fn watch_replay(commands: &mut Commands, replay: Replay) {
+    commands.insert_resource(replay.tuning.clone());
+    commands.insert_resource(MatchSeed(replay.seed));
+    commands.insert_resource(PlayerProfiles(replay.profiles.clone()));
+    // the replay is played like a local match, e.g. the pause menu freezes it
+    commands.insert_resource(LocalHandles {
+        handles: (0..NUM_PLAYERS).collect(),
+    });
+    commands.insert_resource(ReplayPlayback::new(replay));
+}
+
+/// Simulates as many frames of the watched replay as the real time passed and the playback speed ask for
+fn advance_replay(world: &mut World) {
+    if !world.contains_resource::<ReplayPlayback>()
+        || *world.resource::<State<GameState>>().current() != GameState::Playing
+        || world.resource::<Pause>().simulation_frozen
+    {
+        return;
+    }
+    let delta_seconds = world.resource::<Time>().delta_seconds();
+    world.resource_scope(|world, mut playback: Mut<ReplayPlayback>| {
+        playback.advance(world, delta_seconds)
+    });
+}
+
+fn end_replay(mut commands: Commands, ui_query: Query<Entity, With<ReplayUi>>) {
+    commands.remove_resource::<ReplayPlayback>();
+    for entity in ui_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn control_playback(
+    keyboard_input: Res<Input<KeyCode>>,
+    frame_count: Res<FrameCount>,
+    pause: Res<Pause>,
+    playback: Option<ResMut<ReplayPlayback>>,
+) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    if pause.menu_open {
+        return;
+    }
+    let frame = playback.seek_target.unwrap_or(frame_count.frame);
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        playback.paused = !playback.paused;
+    }
+    if keyboard_input.just_pressed(KeyCode::Period) {
+        playback.paused = true;
+        playback.seek(frame + 1);
+    }
+    if keyboard_input.just_pressed(KeyCode::Comma) {
+        playback.paused = true;
+        playback.seek(frame.saturating_sub(1));
+    }
+    if keyboard_input.just_pressed(KeyCode::Right) {
+        playback.seek(frame + SEEK_STEP);
+    }
+    if keyboard_input.just_pressed(KeyCode::Left) {
+        playback.seek(frame.saturating_sub(SEEK_STEP));
+    }
+    if keyboard_input.just_pressed(KeyCode::RBracket) {
+        playback.speed = (playback.speed + 1).min(SPEEDS.len() - 1);
+    }
+    if keyboard_input.just_pressed(KeyCode::LBracket) {
+        playback.speed = playback.speed.saturating_sub(1);
+    }
+    if keyboard_input.just_pressed(KeyCode::Tab) {
+        playback.follow = match playback.follow {
+            None => Some(0),
+            Some(handle) if handle + 1 < NUM_PLAYERS => Some(handle + 1),
+            Some(_) => None,
+        };
+    }
+}
+
+/// Follows the chosen player or moves freely with WASD, the mouse wheel zooms
+fn move_replay_camera(
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    playback: Option<ResMut<ReplayPlayback>>,
+    player_query: Query<(&Player, &Transform), Without<MainCamera>>,
+    mut camera_query: Query<(&mut Transform, &mut OrthographicProjection), With<MainCamera>>,
+) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    let (mut camera_transform, mut projection) = match camera_query.get_single_mut() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    for event in mouse_wheel.iter().filter(|event| event.y != 0.) {
+        projection.scale *= ZOOM_STEP.powf(-event.y.signum());
+    }
+
+    let mut direction = Vec2::ZERO;
+    for (key, key_direction) in [
+        (KeyCode::W, Vec2::Y),
+        (KeyCode::S, -Vec2::Y),
+        (KeyCode::A, -Vec2::X),
+        (KeyCode::D, Vec2::X),
+    ] {
+        if keyboard_input.pressed(key) {
+            direction += key_direction;
+        }
+    }
+    if direction != Vec2::ZERO {
+        playback.follow = None;
+        let movement = direction.normalize() * CAMERA_SPEED * time.delta_seconds();
+        camera_transform.translation += movement.extend(0.);
+    }
+
+    if let Some(handle) = playback.follow {
+        if let Some((_, transform)) = player_query
+            .iter()
+            .find(|(player, _)| player.handle() == handle)
+        {
+            camera_transform.translation.x = transform.translation.x;
+            camera_transform.translation.y = transform.translation.y;
+        }
+    }
+}
+
+#[derive(Component)]
+struct ReplayUi;
+
+#[derive(Component)]
+struct ReplayStatusText;
+
+#[derive(Component)]
+struct TimelineBar;
+
+#[derive(Component)]
+struct TimelineProgress;
+
+fn spawn_replay_ui(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    playback: Option<Res<ReplayPlayback>>,
+) {
+    if playback.is_none() {
+        return;
+    }
+    let text_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 20.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Auto),
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(0.0),
+                    ..default()
+                },
+                flex_direction: FlexDirection::ColumnReverse,
+                padding: Rect::all(Val::Px(10.0)),
+                ..default()
+            },
+            color: Color::NONE.into(),
+            ..default()
+        })
+        .insert(ReplayUi)
+        .insert(Name::new("Replay UI"))
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle {
+                    text: Text::with_section("", text_style.clone(), default()),
+                    ..default()
+                })
+                .insert(ReplayStatusText);
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    "Space pause   , . step   Left Right seek   [ ] speed   Tab follow player   WASD and mouse wheel move the camera",
+                    TextStyle {
+                        font_size: 16.0,
+                        color: Color::rgb(0.6, 0.6, 0.6),
+                        ..text_style
+                    },
+                    default(),
+                ),
+                ..default()
+            });
+            parent
+                .spawn_bundle(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Percent(100.0), Val::Px(12.0)),
+                        margin: Rect {
+                            top: Val::Px(6.0),
+                            ..default()
+                        },
+                        ..default()
+                    },
+                    color: Color::rgb(0.15, 0.15, 0.15).into(),
+                    ..default()
+                })
+                .insert(TimelineBar)
+                .insert(Name::new("Timeline"))
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(NodeBundle {
+                            style: Style {
+                                size: Size::new(Val::Percent(0.0), Val::Percent(100.0)),
+                                ..default()
+                            },
+                            color: Color::rgb(0.8, 0.8, 0.8).into(),
+                            ..default()
+                        })
+                        .insert(TimelineProgress);
+                });
+        });
+}
+
+fn format_frame(frame: u32) -> String {
+    let seconds = frame / FPS as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn update_replay_ui(
+    frame_count: Res<FrameCount>,
+    profiles: Option<Res<PlayerProfiles>>,
+    playback: Option<Res<ReplayPlayback>>,
+    mut text_query: Query<&mut Text, With<ReplayStatusText>>,
+    mut progress_query: Query<&mut Style, With<TimelineProgress>>,
+) {
+    let playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    let frame = frame_count.frame;
+    let state = if playback.seek_target.is_some() {
+        "Seeking".to_string()
+    } else if playback.paused {
+        "Paused".to_string()
+    } else {
+        format!("x{}", playback.speed())
+    };
+    let mut status = format!(
+        "{}   {} / {}   frame {}",
+        state,
+        format_frame(frame),
+        format_frame(playback.frames()),
+        frame
+    );
+    if let (Some(handle), Some(profiles)) = (playback.follow, &profiles) {
+        status += &format!("   following {}", profiles.get(handle).name);
+    }
+    for mut text in text_query.iter_mut() {
+        if text.sections[0].value != status {
+            text.sections[0].value = status.clone();
+        }
+    }
+
+    let progress = frame as f32 / playback.frames().max(1) as f32;
+    for mut style in progress_query.iter_mut() {
+        style.size.width = Val::Percent(progress.min(1.) * 100.);
+    }
+}
+
+/// Clicking or dragging on the timeline seeks to that point of the replay
+fn seek_on_timeline(
+    windows: Res<Windows>,
+    playback: Option<ResMut<ReplayPlayback>>,
+    bar_query: Query<(&Interaction, &Node, &GlobalTransform), With<TimelineBar>>,
+) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    let cursor = match windows.get_primary().and_then(Window::cursor_position) {
+        Some(cursor) => cursor,
+        None => return,
+    };
+    for (interaction, node, transform) in bar_query.iter() {
+        if *interaction != Interaction::Clicked || node.size.x <= 0. {
+            continue;
+        }
+        let left = transform.translation.x - node.size.x / 2.;
+        let fraction = ((cursor.x - left) / node.size.x).clamp(0., 1.);
+        let frame = (fraction * playback.frames() as f32).round() as u32;
+        playback.seek(frame);
+    }
+}
@@ -0,0 +1,57 @@
+use bevy::prelude::*;
+use rand::{Error, RngCore};
+
+/// Seed of the current match, agreed on by all peers while exchanging handshakes.
+/// See [`crate::networking::handshake::Handshake::seed`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchSeed(pub u64);
+
+/// Random numbers for the simulation, e.g. through [`rand::Rng::gen_range`].
+/// As it is rolled back and seeded with the [`MatchSeed`], every peer and every replay draws the same numbers.
+/// Rollback systems have to draw in the same order on every peer, e.g. by iterating players sorted by their handle.
+#[derive(Reflect, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect(Resource)]
+pub struct GameRng {
+    state: u64,
+}
+
+impl GameRng {
+    pub fn new(seed: MatchSeed) -> Self {
+        Self { state: seed.0 }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+}
+
+/// SplitMix64, which only needs integer arithmetic and therefore gives the same numbers on every platform
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+pub fn reset_rng(seed: Res<MatchSeed>, mut rng: ResMut<GameRng>) {
+    *rng = GameRng::new(*seed);
+}
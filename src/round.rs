@@ -0,0 +1,89 @@
+use crate::combat::{Bullet, FireCooldown, Health};
+use crate::config::FPS;
+use crate::dash::Dash;
+use crate::player::{spawn_position, Player};
+use crate::position::Position;
+use crate::weapon::{Weapon, WeaponPickup};
+use crate::GameState;
+use bevy::prelude::*;
+
+pub struct RoundPlugin;
+
+/// Length of a round in frames
+pub const ROUND_DURATION: u32 = 90 * FPS as u32;
+
+/// This plugin resets the [`Round`] when a match starts.
+/// The round itself is simulated in the rollback schedule, see [`crate::networking::NetworkingPlugin`].
+impl Plugin for RoundPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Round>()
+            .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(reset_round));
+    }
+}
+
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect(Resource)]
+pub struct Round {
+    /// Starts at 1
+    pub number: u32,
+    pub frames_left: u32,
+}
+
+impl Default for Round {
+    fn default() -> Self {
+        Self {
+            number: 1,
+            frames_left: ROUND_DURATION,
+        }
+    }
+}
+
+impl Round {
+    pub fn seconds_left(&self) -> u32 {
+        (self.frames_left + FPS as u32 - 1) / FPS as u32
+    }
+}
+
+fn reset_round(mut round: ResMut<Round>) {
+    *round = default();
+}
+
+/// Rollback system counting down the round. When time is up, everyone respawns for the next round
+/// with the first weapon, and all pickups are back.
+pub fn advance_round(
+    mut commands: Commands,
+    mut round: ResMut<Round>,
+    mut player_query: Query<(
+        &Player,
+        &mut Position,
+        &mut Health,
+        &mut FireCooldown,
+        &mut Weapon,
+        &mut Dash,
+    )>,
+    mut pickup_query: Query<&mut WeaponPickup>,
+    bullet_query: Query<Entity, With<Bullet>>,
+) {
+    round.frames_left = round.frames_left.saturating_sub(1);
+    if round.frames_left > 0 {
+        return;
+    }
+
+    round.number += 1;
+    round.frames_left = ROUND_DURATION;
+    for (player, mut position, mut health, mut cooldown, mut weapon, mut dash) in
+        player_query.iter_mut()
+    {
+        position.0 = spawn_position(player.handle());
+        *health = Health::full(health.max);
+        *cooldown = default();
+        *weapon = default();
+        *dash = default();
+    }
+    for mut pickup in pickup_query.iter_mut() {
+        pickup.respawn_frames_left = 0;
+    }
+    for bullet in bullet_query.iter() {
+        commands.entity(bullet).despawn();
+    }
+}
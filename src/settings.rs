@@ -0,0 +1,447 @@
+use crate::loading::FontAssets;
+use crate::menu::ButtonColors;
+use crate::storage;
+use bevy::{log, prelude::*, window::PresentMode, window::WindowMode};
+use serde::{Deserialize, Serialize};
+
+pub struct SettingsPlugin;
+
+const SETTINGS_KEY: &str = "settings.ron";
+const RESOLUTIONS: [(f32, f32); 4] = [(800., 600.), (1280., 720.), (1600., 900.), (1920., 1080.)];
+const MSAA_SAMPLES: [u32; 2] = [1, 4];
+
+/// This plugin owns the user's [`Settings`], applies them whenever they change and draws the settings screen.
+/// The settings screen can be opened from the main menu as well as from the pause menu.
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<Settings>() {
+            app.insert_resource(Settings::load());
+        }
+        app.add_system(apply_video_settings)
+            .add_system(click_settings_buttons)
+            .add_system(update_setting_values);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub master_volume: f32,
+    pub music_volume: f32,
+    pub sfx_volume: f32,
+    pub display_mode: DisplayMode,
+    pub resolution: (f32, f32),
+    pub vsync: bool,
+    pub msaa_samples: u32,
+    pub correction_smoothing: Smoothing,
+    pub kill_cam: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            master_volume: 1.0,
+            music_volume: 0.3,
+            sfx_volume: 0.3,
+            display_mode: DisplayMode::Windowed,
+            resolution: RESOLUTIONS[0],
+            vsync: true,
+            msaa_samples: 4,
+            correction_smoothing: Smoothing::Medium,
+            kill_cam: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplayMode {
+    Windowed,
+    BorderlessFullscreen,
+    Fullscreen,
+}
+
+impl From<DisplayMode> for WindowMode {
+    fn from(mode: DisplayMode) -> Self {
+        match mode {
+            DisplayMode::Windowed => WindowMode::Windowed,
+            DisplayMode::BorderlessFullscreen => WindowMode::BorderlessFullscreen,
+            DisplayMode::Fullscreen => WindowMode::Fullscreen,
+        }
+    }
+}
+
+/// How quickly rendered positions catch up with positions corrected by a rollback
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Smoothing {
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl Smoothing {
+    /// Time it takes a correction to shrink to about a third
+    pub fn seconds(self) -> f32 {
+        match self {
+            Smoothing::Off => 0.,
+            Smoothing::Low => 0.05,
+            Smoothing::Medium => 0.1,
+            Smoothing::High => 0.2,
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the settings saved by [`Settings::save`], falling back to the defaults
+    pub fn load() -> Self {
+        let settings = match storage::read(SETTINGS_KEY) {
+            Some(settings) => settings,
+            None => return default(),
+        };
+        ron::from_str(&settings).unwrap_or_else(|error| {
+            log::warn!("Failed to read settings, using defaults: {}", error);
+            default()
+        })
+    }
+
+    pub fn save(&self) {
+        let result = ron::ser::to_string_pretty(self, default())
+            .map_err(|error| error.to_string())
+            .and_then(|settings| storage::write(SETTINGS_KEY, &settings));
+        if let Err(error) = result {
+            log::error!("Failed to save settings: {}", error);
+        }
+    }
+
+    pub fn present_mode(&self) -> PresentMode {
+        if self.vsync {
+            PresentMode::Fifo
+        } else {
+            PresentMode::Immediate
+        }
+    }
+
+    pub fn window_descriptor(&self) -> WindowDescriptor {
+        WindowDescriptor {
+            width: self.resolution.0,
+            height: self.resolution.1,
+            mode: self.display_mode.into(),
+            present_mode: self.present_mode(),
+            ..default()
+        }
+    }
+
+    fn step(&mut self, setting: Setting, step: i32) {
+        match setting {
+            Setting::MasterVolume => step_volume(&mut self.master_volume, step),
+            Setting::MusicVolume => step_volume(&mut self.music_volume, step),
+            Setting::SfxVolume => step_volume(&mut self.sfx_volume, step),
+            Setting::DisplayMode => {
+                let modes = [
+                    DisplayMode::Windowed,
+                    DisplayMode::BorderlessFullscreen,
+                    DisplayMode::Fullscreen,
+                ];
+                self.display_mode = cycle(&modes, &self.display_mode, step);
+            }
+            Setting::Resolution => self.resolution = cycle(&RESOLUTIONS, &self.resolution, step),
+            Setting::Vsync => self.vsync = !self.vsync,
+            Setting::Msaa => self.msaa_samples = cycle(&MSAA_SAMPLES, &self.msaa_samples, step),
+            Setting::Smoothing => {
+                let options = [
+                    Smoothing::Off,
+                    Smoothing::Low,
+                    Smoothing::Medium,
+                    Smoothing::High,
+                ];
+                self.correction_smoothing = cycle(&options, &self.correction_smoothing, step);
+            }
+            Setting::KillCam => self.kill_cam = !self.kill_cam,
+        }
+    }
+
+    fn display(&self, setting: Setting) -> String {
+        match setting {
+            Setting::MasterVolume => format!("{:.0}%", self.master_volume * 100.),
+            Setting::MusicVolume => format!("{:.0}%", self.music_volume * 100.),
+            Setting::SfxVolume => format!("{:.0}%", self.sfx_volume * 100.),
+            Setting::DisplayMode => match self.display_mode {
+                DisplayMode::Windowed => "Windowed".to_string(),
+                DisplayMode::BorderlessFullscreen => "Borderless".to_string(),
+                DisplayMode::Fullscreen => "Fullscreen".to_string(),
+            },
+            Setting::Resolution => format!("{}x{}", self.resolution.0, self.resolution.1),
+            Setting::Vsync => if self.vsync { "On" } else { "Off" }.to_string(),
+            Setting::Msaa => format!("{}x", self.msaa_samples),
+            Setting::Smoothing => format!("{:?}", self.correction_smoothing),
+            Setting::KillCam => if self.kill_cam { "On" } else { "Off" }.to_string(),
+        }
+    }
+}
+
+fn step_volume(volume: &mut f32, step: i32) {
+    *volume = (*volume + step as f32 * 0.1).clamp(0., 1.);
+}
+
+/// Returns the option `step` places after `current`, wrapping around.
+/// Unknown values, e.g. from a hand edited settings file, start over at the first option.
+fn cycle<T: Copy + PartialEq>(options: &[T], current: &T, step: i32) -> T {
+    let index = match options.iter().position(|option| option == current) {
+        Some(index) => (index as i32 + step).rem_euclid(options.len() as i32) as usize,
+        None => 0,
+    };
+    options[index]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Setting {
+    MasterVolume,
+    MusicVolume,
+    SfxVolume,
+    DisplayMode,
+    Resolution,
+    Vsync,
+    Msaa,
+    Smoothing,
+    KillCam,
+}
+
+impl Setting {
+    const ALL: [Setting; 9] = [
+        Setting::MasterVolume,
+        Setting::MusicVolume,
+        Setting::SfxVolume,
+        Setting::DisplayMode,
+        Setting::Resolution,
+        Setting::Vsync,
+        Setting::Msaa,
+        Setting::Smoothing,
+        Setting::KillCam,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Setting::MasterVolume => "Master volume",
+            Setting::MusicVolume => "Music volume",
+            Setting::SfxVolume => "Effects volume",
+            Setting::DisplayMode => "Window mode",
+            Setting::Resolution => "Resolution",
+            Setting::Vsync => "VSync",
+            Setting::Msaa => "Anti-aliasing",
+            Setting::Smoothing => "Smoothing",
+            Setting::KillCam => "Kill cam",
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct SettingsMenu;
+
+#[derive(Component, Clone, Copy)]
+enum SettingsButton {
+    Step(Setting, i32),
+    Back,
+}
+
+/// Marks the text showing the current value of a setting
+#[derive(Component)]
+struct SettingValue(Setting);
+
+/// Draws the settings screen on top of everything else
+pub fn open_settings_menu(
+    commands: &mut Commands,
+    font_assets: &FontAssets,
+    button_colors: &ButtonColors,
+    settings: &Settings,
+) {
+    let text_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 30.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                position_type: PositionType::Absolute,
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: Color::rgb(0.1, 0.1, 0.1).into(),
+            ..Default::default()
+        })
+        .insert(SettingsMenu)
+        .insert(Name::new("Settings Menu"))
+        .with_children(|parent| {
+            for setting in Setting::ALL {
+                parent
+                    .spawn_bundle(NodeBundle {
+                        style: Style {
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        color: Color::NONE.into(),
+                        ..Default::default()
+                    })
+                    .insert(Name::new(setting.label()))
+                    .with_children(|parent| {
+                        parent.spawn_bundle(TextBundle {
+                            style: Style {
+                                size: Size::new(Val::Px(250.0), Val::Auto),
+                                ..Default::default()
+                            },
+                            text: Text::with_section(
+                                setting.label(),
+                                text_style.clone(),
+                                Default::default(),
+                            ),
+                            ..Default::default()
+                        });
+                        spawn_settings_button(
+                            parent,
+                            SettingsButton::Step(setting, -1),
+                            "<",
+                            40.0,
+                            &text_style,
+                            button_colors,
+                        );
+                        parent
+                            .spawn_bundle(TextBundle {
+                                style: Style {
+                                    size: Size::new(Val::Px(180.0), Val::Auto),
+                                    ..Default::default()
+                                },
+                                text: Text::with_section(
+                                    settings.display(setting),
+                                    text_style.clone(),
+                                    TextAlignment {
+                                        horizontal: HorizontalAlign::Center,
+                                        ..Default::default()
+                                    },
+                                ),
+                                ..Default::default()
+                            })
+                            .insert(SettingValue(setting));
+                        spawn_settings_button(
+                            parent,
+                            SettingsButton::Step(setting, 1),
+                            ">",
+                            40.0,
+                            &text_style,
+                            button_colors,
+                        );
+                    });
+            }
+            spawn_settings_button(
+                parent,
+                SettingsButton::Back,
+                "Back",
+                120.0,
+                &text_style,
+                button_colors,
+            );
+        });
+}
+
+fn spawn_settings_button(
+    parent: &mut ChildBuilder,
+    button: SettingsButton,
+    label: &str,
+    width: f32,
+    text_style: &TextStyle,
+    button_colors: &ButtonColors,
+) {
+    parent
+        .spawn_bundle(ButtonBundle {
+            style: Style {
+                size: Size::new(Val::Px(width), Val::Px(40.0)),
+                margin: Rect::all(Val::Px(5.0)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            color: button_colors.normal,
+            ..Default::default()
+        })
+        .insert(button)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(label, text_style.clone(), Default::default()),
+                ..Default::default()
+            });
+        });
+}
+
+/// Saves the settings and removes the settings screen, if it is open
+pub fn close_settings_menu(
+    commands: &mut Commands,
+    settings: &Settings,
+    menu_query: &Query<Entity, With<SettingsMenu>>,
+) {
+    if menu_query.is_empty() {
+        return;
+    }
+    settings.save();
+    for menu in menu_query.iter() {
+        commands.entity(menu).despawn_recursive();
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn click_settings_buttons(
+    mut commands: Commands,
+    button_colors: Res<ButtonColors>,
+    mut settings: ResMut<Settings>,
+    menu_query: Query<Entity, With<SettingsMenu>>,
+    mut interaction_query: Query<
+        (&SettingsButton, &Interaction, &mut UiColor),
+        Changed<Interaction>,
+    >,
+) {
+    for (button, interaction, mut color) in interaction_query.iter_mut() {
+        match *interaction {
+            Interaction::Clicked => match *button {
+                SettingsButton::Step(setting, step) => settings.step(setting, step),
+                SettingsButton::Back => close_settings_menu(&mut commands, &settings, &menu_query),
+            },
+            Interaction::Hovered => {
+                *color = button_colors.hovered;
+            }
+            Interaction::None => {
+                *color = button_colors.normal;
+            }
+        }
+    }
+}
+
+fn update_setting_values(
+    settings: Res<Settings>,
+    mut text_query: Query<(&mut Text, &SettingValue)>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    for (mut text, value) in text_query.iter_mut() {
+        text.sections[0].value = settings.display(value.0);
+    }
+}
+
+fn apply_video_settings(
+    settings: Res<Settings>,
+    mut windows: ResMut<Windows>,
+    mut msaa: ResMut<Msaa>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    if let Some(window) = windows.get_primary_mut() {
+        window.set_mode(settings.display_mode.into());
+        window.set_resolution(settings.resolution.0, settings.resolution.1);
+        window.set_present_mode(settings.present_mode());
+    }
+    if msaa.samples != settings.msaa_samples {
+        msaa.samples = settings.msaa_samples;
+    }
+}
@@ -0,0 +1,64 @@
+//! Persists small pieces of data like the settings between runs.
+//! On native they are stored as files in the user's data directory, on wasm in the browser's local storage.
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::{read, remove, write};
+#[cfg(target_arch = "wasm32")]
+pub use wasm::{read, remove, write};
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use directories::ProjectDirs;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn path(key: &str) -> Option<PathBuf> {
+        // ToDo: use your own game name here
+        let dirs = ProjectDirs::from("ch", "hohenheim", "extreme-bevy")?;
+        Some(dirs.data_dir().join(key))
+    }
+
+    pub fn read(key: &str) -> Option<String> {
+        fs::read_to_string(path(key)?).ok()
+    }
+
+    pub fn write(key: &str, contents: &str) -> Result<(), String> {
+        let path = path(key).ok_or("Failed to find a data directory")?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+        }
+        fs::write(path, contents).map_err(|error| error.to_string())
+    }
+
+    pub fn remove(key: &str) -> Result<(), String> {
+        let path = path(key).ok_or("Failed to find a data directory")?;
+        fs::remove_file(path).map_err(|error| error.to_string())
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use web_sys::Storage;
+
+    fn local_storage() -> Option<Storage> {
+        web_sys::window()?.local_storage().ok()?
+    }
+
+    pub fn read(key: &str) -> Option<String> {
+        local_storage()?.get_item(key).ok()?
+    }
+
+    pub fn write(key: &str, contents: &str) -> Result<(), String> {
+        local_storage()
+            .ok_or("Local storage is not available")?
+            .set_item(key, contents)
+            .map_err(|error| format!("{:?}", error))
+    }
+
+    pub fn remove(key: &str) -> Result<(), String> {
+        local_storage()
+            .ok_or("Local storage is not available")?
+            .remove_item(key)
+            .map_err(|error| format!("{:?}", error))
+    }
+}
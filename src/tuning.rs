@@ -0,0 +1,208 @@
+use crate::config::NUM_PLAYERS;
+use crate::loading::TuningAssets;
+use crate::networking::protocol::LocalHandles;
+use crate::replay::viewer::ReplayPlayback;
+use crate::weapon::{PickupSpawn, WeaponDefinition};
+use crate::GameState;
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    log,
+    prelude::*,
+    reflect::TypeUuid,
+};
+use serde::{Deserialize, Serialize};
+
+pub struct TuningPlugin;
+
+/// This plugin loads the gameplay [`Tuning`] from `*.tuning.ron` assets.
+/// Changes to the asset, e.g. through hot reloading, are applied as long as the tuning is not locked.
+/// It is locked while we connect to or play with peers, as everyone has to simulate with identical values,
+/// and while a replay is watched.
+impl Plugin for TuningPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Tuning>()
+            .init_asset_loader::<TuningLoader>()
+            .init_resource::<Tuning>()
+            .add_system(update_tuning);
+    }
+}
+
+/// Values the simulation is based on. Durations are in rollback frames, speeds in units per second.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TypeUuid)]
+#[uuid = "8c1f4d52-6a0e-4c1b-9f3e-2b7d5e0a9c41"]
+#[serde(default)]
+pub struct Tuning {
+    pub player_speed: f32,
+    pub dash_speed: f32,
+    /// Frames a dash moves the player
+    pub dash_duration: u32,
+    /// Frames from the start of a dash until the player can dash again
+    pub dash_cooldown: u32,
+    /// Frames from the start of a dash during which bullets pass through the player
+    pub dash_invulnerability: u32,
+    pub max_health: u32,
+    /// Players start with the first weapon and fall back to it when they run out of ammo or die
+    pub weapons: Vec<WeaponDefinition>,
+    /// Bullets closer to a player than this hit them
+    pub hit_radius: f32,
+    pub pickups: Vec<PickupSpawn>,
+    /// Players respawn at a random one of these after dying, or where they started if there are none
+    pub respawn_points: Vec<(f32, f32)>,
+    /// Players closer to a pickup than this pick it up
+    pub pickup_radius: f32,
+    /// Frames until a pickup is back after it was picked up
+    pub pickup_respawn: u32,
+    /// Used for players without a profile, e.g. in local matches
+    pub player_colors: [[f32; 3]; NUM_PLAYERS],
+    /// Returned by [`Self::weapon`] for indices without a weapon, so it can hand out references
+    #[serde(skip)]
+    fallback_weapon: WeaponDefinition,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            player_speed: 15.,
+            dash_speed: 40.,
+            dash_duration: 9,
+            dash_cooldown: 90,
+            dash_invulnerability: 12,
+            max_health: 3,
+            weapons: vec![
+                WeaponDefinition::default(),
+                WeaponDefinition {
+                    name: "Shotgun".to_string(),
+                    fire_cooldown: 45,
+                    projectiles: 5,
+                    spread: 0.3,
+                    projectile_speed: 18.,
+                    projectile_lifetime: 30,
+                    ammo: Some(6),
+                    color: [0.9, 0.5, 0.1],
+                    ..default()
+                },
+                WeaponDefinition {
+                    name: "Machine gun".to_string(),
+                    fire_cooldown: 6,
+                    spread: 0.05,
+                    projectile_speed: 25.,
+                    projectile_lifetime: 60,
+                    ammo: Some(30),
+                    color: [0.2, 0.6, 0.9],
+                    ..default()
+                },
+                WeaponDefinition {
+                    name: "Railgun".to_string(),
+                    fire_cooldown: 60,
+                    projectile_speed: 45.,
+                    projectile_lifetime: 60,
+                    damage: 3,
+                    ammo: Some(3),
+                    color: [0.7, 0.2, 0.9],
+                    ..default()
+                },
+            ],
+            hit_radius: 0.5,
+            pickups: vec![
+                PickupSpawn {
+                    position: (0., 3.),
+                    weapons: vec![1, 2],
+                },
+                PickupSpawn {
+                    position: (0., -3.),
+                    weapons: vec![2, 1],
+                },
+                PickupSpawn {
+                    position: (0., 0.),
+                    weapons: vec![3],
+                },
+            ],
+            respawn_points: vec![
+                (-6., 4.),
+                (6., 4.),
+                (-6., -4.),
+                (6., -4.),
+                (-2., 0.),
+                (2., 0.),
+            ],
+            pickup_radius: 0.5,
+            pickup_respawn: 600,
+            player_colors: [[0.0, 0.8, 0.0], [0.8, 0.0, 0.0]],
+            fallback_weapon: WeaponDefinition::default(),
+        }
+    }
+}
+
+impl Tuning {
+    /// Hash of all values, exchanged with our peers to make sure everyone simulates the same game
+    pub fn hash(&self) -> u64 {
+        let serialized = ron::to_string(self).expect("Failed to serialize tuning");
+        // FNV-1a, as the hash has to be the same on every platform
+        serialized.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    /// The weapon at `index` in [`Self::weapons`], or the default weapon if there is none
+    pub fn weapon(&self, index: usize) -> &WeaponDefinition {
+        self.weapons.get(index).unwrap_or(&self.fallback_weapon)
+    }
+
+    pub fn player_color(&self, handle: usize) -> Color {
+        let [r, g, b] = self.player_colors[handle % NUM_PLAYERS];
+        Color::rgb(r, g, b)
+    }
+}
+
+#[derive(Default)]
+struct TuningLoader;
+
+impl AssetLoader for TuningLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let tuning = ron::de::from_bytes::<Tuning>(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(tuning));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tuning.ron"]
+    }
+}
+
+/// Copies the tuning asset into the [`Tuning`] resource used by the simulation, unless it is locked
+fn update_tuning(
+    mut tuning: ResMut<Tuning>,
+    tunings: Res<Assets<Tuning>>,
+    tuning_assets: Option<Res<TuningAssets>>,
+    state: Res<State<GameState>>,
+    local_handles: Option<Res<LocalHandles>>,
+    replay: Option<Res<ReplayPlayback>>,
+) {
+    // replays are simulated with the tuning they were recorded with
+    let locked = replay.is_some()
+        || match state.current() {
+            GameState::Connecting => true,
+            // local matches can be tuned while playing
+            GameState::Playing => local_handles.map_or(true, |local_handles| {
+                local_handles.handles.len() != NUM_PLAYERS
+            }),
+            GameState::Loading | GameState::Menu => false,
+        };
+    if locked {
+        return;
+    }
+    let asset = match tuning_assets.and_then(|assets| tunings.get(&assets.gameplay)) {
+        Some(asset) => asset,
+        None => return,
+    };
+    if *tuning != *asset {
+        log::info!("Applying gameplay tuning: {:?}", asset);
+        *tuning = asset.clone();
+    }
+}
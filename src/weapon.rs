@@ -0,0 +1,216 @@
+use crate::combat::FireCooldown;
+use crate::player::Player;
+use crate::position::{CorrectionOffset, Position};
+use crate::rng::GameRng;
+use crate::tuning::Tuning;
+use crate::GameState;
+use bevy::{prelude::*, render::texture::DEFAULT_IMAGE_HANDLE};
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+pub struct WeaponPlugin;
+
+const PICKUP_SIZE: f32 = 0.5;
+
+/// This plugin spawns the weapon pickups of the map and shows whether they can be picked up.
+/// Picking them up and firing are simulated in the rollback schedule, see [`crate::networking::NetworkingPlugin`].
+impl Plugin for WeaponPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_enter(GameState::Playing).with_system(spawn_pickups))
+            .add_system_set(SystemSet::on_update(GameState::Playing).with_system(show_pickups))
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(despawn_pickups));
+    }
+}
+
+/// A kind of weapon, see [`Tuning::weapons`]. Durations are in rollback frames, speeds in units per second.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WeaponDefinition {
+    pub name: String,
+    /// Frames between two shots
+    pub fire_cooldown: u32,
+    /// Bullets fired with every shot
+    pub projectiles: u32,
+    /// How far bullets deviate sideways at most, relative to their direction.
+    /// Every bullet deviates by a random amount, e.g. `0.2` for up to about 11° to either side.
+    pub spread: f32,
+    pub projectile_speed: f32,
+    pub projectile_lifetime: u32,
+    /// Health a hit takes
+    pub damage: u32,
+    /// Shots until the player falls back to the first weapon, `None` for unlimited ammo
+    pub ammo: Option<u32>,
+    /// Color of the pickups handing out this weapon
+    pub color: [f32; 3],
+}
+
+impl Default for WeaponDefinition {
+    fn default() -> Self {
+        Self {
+            name: "Pistol".to_string(),
+            fire_cooldown: 20,
+            projectiles: 1,
+            spread: 0.,
+            projectile_speed: 20.,
+            projectile_lifetime: 120,
+            damage: 1,
+            ammo: None,
+            color: [0.8, 0.8, 0.8],
+        }
+    }
+}
+
+impl WeaponDefinition {
+    /// Directions of the bullets of a shot towards `direction`, which has to be normalized.
+    /// Only uses `normalize`, as trigonometric functions may round differently on other platforms.
+    pub fn projectile_directions(&self, direction: Vec2, rng: &mut GameRng) -> Vec<Vec2> {
+        (0..self.projectiles.max(1))
+            .map(|_| {
+                if self.spread <= 0. {
+                    return direction;
+                }
+                let offset = rng.gen_range(-self.spread..=self.spread);
+                (direction + direction.perp() * offset).normalize()
+            })
+            .collect()
+    }
+
+    pub fn color(&self) -> Color {
+        let [r, g, b] = self.color;
+        Color::rgb(r, g, b)
+    }
+}
+
+/// Where a weapon pickup is placed on the map, see [`Tuning::pickups`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PickupSpawn {
+    pub position: (f32, f32),
+    /// Indices into [`Tuning::weapons`]. The pickup starts with the first one
+    /// and hands out a random one of them every time it is back.
+    pub weapons: Vec<usize>,
+}
+
+/// The weapon a player holds
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct Weapon {
+    /// Index into [`Tuning::weapons`], players start with the first one
+    pub index: usize,
+    /// Shots left, only counted for weapons with limited ammo
+    pub ammo: u32,
+}
+
+impl Weapon {
+    pub fn new(index: usize, tuning: &Tuning) -> Self {
+        Self {
+            index,
+            ammo: tuning.weapon(index).ammo.unwrap_or(0),
+        }
+    }
+}
+
+/// A place on the map where players pick up a weapon by walking over it
+#[derive(Component, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct WeaponPickup {
+    /// Index into [`Tuning::pickups`]
+    pub spawn: usize,
+    /// Index into [`Tuning::weapons`] of the weapon it hands out
+    pub weapon: usize,
+    /// Frames until the pickup is back, 0 while it can be picked up
+    pub respawn_frames_left: u32,
+}
+
+/// Everything about a pickup that is simulated in the rollback schedule, without any rendering
+pub fn pickup_bundle(spawn: usize, tuning: &Tuning) -> impl Bundle {
+    let pickup = &tuning.pickups[spawn];
+    let (x, y) = pickup.position;
+    (
+        Position(Vec2::new(x, y)),
+        WeaponPickup {
+            spawn,
+            weapon: pickup.weapons.first().copied().unwrap_or_default(),
+            respawn_frames_left: 0,
+        },
+    )
+}
+
+fn spawn_pickups(
+    mut commands: Commands,
+    mut rollback_id_provider: ResMut<RollbackIdProvider>,
+    tuning: Res<Tuning>,
+) {
+    for (spawn, pickup) in tuning.pickups.iter().enumerate() {
+        let (x, y) = pickup.position;
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::splat(PICKUP_SIZE)),
+                    ..default()
+                },
+                texture: DEFAULT_IMAGE_HANDLE.typed(),
+                transform: Transform::from_xyz(x, y, -1.),
+                ..default()
+            })
+            .insert(Name::new("Weapon Pickup"))
+            .insert_bundle(pickup_bundle(spawn, &tuning))
+            .insert(CorrectionOffset::default())
+            .insert(Rollback::new(rollback_id_provider.next_id()));
+    }
+}
+
+/// Pickups are colored like the weapon they hand out and hidden until they are back after being picked up
+fn show_pickups(
+    tuning: Res<Tuning>,
+    mut pickup_query: Query<(&WeaponPickup, &mut Sprite, &mut Visibility)>,
+) {
+    for (pickup, mut sprite, mut visibility) in pickup_query.iter_mut() {
+        visibility.is_visible = pickup.respawn_frames_left == 0;
+        let color = tuning.weapon(pickup.weapon).color();
+        if sprite.color != color {
+            sprite.color = color;
+        }
+    }
+}
+
+fn despawn_pickups(mut commands: Commands, pickup_query: Query<Entity, With<WeaponPickup>>) {
+    for entity in pickup_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/// Rollback system handing the weapon of a pickup to the player walking over it
+pub fn pick_up_weapons(
+    tuning: Res<Tuning>,
+    mut rng: ResMut<GameRng>,
+    mut pickup_query: Query<(&Position, &mut WeaponPickup), Without<Player>>,
+    mut player_query: Query<(&Position, &Player, &mut Weapon, &mut FireCooldown)>,
+) {
+    let mut pickups: Vec<_> = pickup_query.iter_mut().collect();
+    // random weapons have to be drawn in the same order on every peer
+    pickups.sort_by_key(|(_, pickup)| pickup.spawn);
+    // when two players reach a pickup in the same frame, the same one has to get it on every peer
+    let mut players: Vec<_> = player_query.iter_mut().collect();
+    players.sort_by_key(|(_, player, _, _)| player.handle());
+
+    for (position, mut pickup) in pickups {
+        if pickup.respawn_frames_left > 0 {
+            pickup.respawn_frames_left -= 1;
+            if pickup.respawn_frames_left == 0 {
+                if let Some(spawn) = tuning.pickups.get(pickup.spawn) {
+                    pickup.weapon = spawn.weapons.choose(&mut *rng).copied().unwrap_or_default();
+                }
+            }
+            continue;
+        }
+        let player = players.iter_mut().find(|(player_position, _, _, _)| {
+            player_position.0.distance(position.0) <= tuning.pickup_radius
+        });
+        if let Some((_, _, weapon, cooldown)) = player {
+            **weapon = Weapon::new(pickup.weapon, &tuning);
+            **cooldown = default();
+            pickup.respawn_frames_left = tuning.pickup_respawn;
+        }
+    }
+}